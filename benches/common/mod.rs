@@ -0,0 +1,50 @@
+// Programs and inputs shared by the benchmarks, and by tests/differential.rs through
+// `#[path]`. Not every target uses all of them.
+#![allow(dead_code)]
+
+use datalogint::Fact;
+
+pub const TRANSITIVE_CLOSURE: &str = r#"
+.decl edge(x, y) .input;
+.decl path(x, y) .output;
+.rule path(x, y) :- 1 edge(x, y);
+.rule path(x, z) :- 2 path(x, y), edge(y, z);
+"#;
+
+// xorshift, so that every run sees the same inputs
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new() -> Rng {
+        Rng(0x2545_f491_4f6c_dd1d)
+    }
+
+    // A generator of its own for every seed, e.g. one per test case so that a failing case
+    // can be rerun on its own
+    pub fn with_seed(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    pub fn below_usize(&mut self, n: usize) -> usize {
+        self.below(n as u64) as usize
+    }
+}
+
+// `edges_per_node` edges from every one of `nodes` nodes to random nodes
+pub fn random_edges(nodes: u64, edges_per_node: u64) -> Vec<Fact> {
+    let mut rng = Rng::new();
+    (0..nodes)
+        .flat_map(|from| (0..edges_per_node).map(move |_| from))
+        .map(|from| Fact {
+            name: "edge".to_string(),
+            params: vec![format!("n{}", from), format!("n{}", rng.below(nodes))],
+        })
+        .collect()
+}
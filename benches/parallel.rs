@@ -0,0 +1,63 @@
+// Transitive closure of a generated random graph, evaluated with an increasing number of
+// workers. Run with `cargo bench --bench parallel`, optionally followed by the number of
+// nodes, the number of edges per node and the largest number of workers (by default the
+// number of cores).
+mod common;
+
+use common::{random_edges, TRANSITIVE_CLOSURE};
+use datalogint::{parse::parse_program, run_datalog_with_options, RunOptions, Token};
+use logos::Logos;
+use std::env;
+use std::thread;
+use std::time::Instant;
+
+fn main() {
+    // cargo bench passes --bench, skip the flags
+    let args = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect::<Vec<_>>();
+    let nodes = args
+        .first()
+        .map_or(400, |n| n.parse().expect("nodes is a number"));
+    let edges_per_node = args
+        .get(1)
+        .map_or(2, |n| n.parse().expect("edges is a number"));
+
+    let program = parse_program(&mut Token::lexer(TRANSITIVE_CLOSURE)).unwrap();
+    let edges = random_edges(nodes, edges_per_node);
+    let max_workers = args.get(2).map_or_else(
+        || thread::available_parallelism().map_or(4, |n| n.get()),
+        |n| n.parse().expect("workers is a number"),
+    );
+    let mut worker_counts = vec![1];
+    while worker_counts.last().unwrap() * 2 <= max_workers {
+        worker_counts.push(worker_counts.last().unwrap() * 2);
+    }
+
+    println!(
+        "transitive closure of {} nodes and {} edges",
+        nodes,
+        edges.len()
+    );
+    let mut sequential = None;
+    for workers in worker_counts {
+        let options = RunOptions {
+            workers,
+            ..RunOptions::default()
+        };
+        let start = Instant::now();
+        let database = run_datalog_with_options(&program, edges.clone(), &options)
+            .unwrap()
+            .database;
+        let elapsed = start.elapsed();
+        let baseline = *sequential.get_or_insert(elapsed);
+        println!(
+            "{:>2} workers: {:>8.3}s, {} paths, speedup {:.2}x",
+            workers,
+            elapsed.as_secs_f64(),
+            database.len(),
+            baseline.as_secs_f64() / elapsed.as_secs_f64()
+        );
+    }
+}
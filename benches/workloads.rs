@@ -0,0 +1,190 @@
+// Generated workloads of increasing size: transitive closure, same generation and triangles
+// on random graphs evaluated with run_datalog, and liveness analysis of synthetic Bril
+// functions with perform_liveness_analysis. Run with `cargo bench --bench workloads`,
+// optionally followed by the number of sizes to run (by default 3, every size doubling the
+// previous one) and a workload name to run only that one.
+mod common;
+
+use common::{random_edges, Rng, TRANSITIVE_CLOSURE};
+use datalogint::bril_rs_personal::BBProgram;
+use datalogint::optimize_bril::perform_liveness_analysis;
+use datalogint::{parse::parse_program, parse_bril, run_datalog, Fact, Program, Token};
+use logos::Logos;
+use serde_json::{json, Value};
+use std::env;
+use std::time::{Duration, Instant};
+
+const SAME_GENERATION: &str = r#"
+.decl parent(x, p) .input;
+.decl sg(x, y) .output;
+.rule sg(x, y) :- 2 parent(x, p), parent(y, p);
+.rule sg(x, y) :- 3 parent(x, a), sg(a, b), parent(y, b);
+"#;
+
+const TRIANGLES: &str = r#"
+.decl edge(x, y) .input;
+.decl triangle(x, y, z) .output;
+.rule triangle(x, y, z) :- 3 edge(x, y), edge(y, z), edge(z, x);
+"#;
+
+fn fact(name: &str, params: &[String]) -> Fact {
+    Fact {
+        name: name.to_string(),
+        params: params.to_vec(),
+    }
+}
+
+// A random tree: the parent of every node but the root is one of the nodes before it, in
+// the same or the previous level of a tree with `fanout` children per node on average
+fn random_tree(nodes: u64, fanout: u64) -> Vec<Fact> {
+    let mut rng = Rng::new();
+    (1..nodes)
+        .map(|node| {
+            let parent = (node - 1) / fanout;
+            let parent = parent.saturating_sub(rng.below(2));
+            fact("parent", &[format!("n{}", node), format!("n{}", parent)])
+        })
+        .collect()
+}
+
+// A function of `blocks` basic blocks, each defining a few variables from the variables of
+// the blocks before it and branching to the next block or back to an earlier one, so that
+// variables stay live around loops
+fn synthetic_function(blocks: u64) -> String {
+    let mut rng = Rng::new();
+    let mut instrs: Vec<Value> = vec![
+        json!({"dest": "c", "op": "const", "type": "bool", "value": true}),
+        json!({"dest": "v0", "op": "const", "type": "int", "value": 0}),
+        json!({"dest": "v1", "op": "const", "type": "int", "value": 1}),
+    ];
+    for block in 0..blocks {
+        let var = |offset: u64| format!("v{}", (block + 2).saturating_sub(offset));
+        instrs.push(json!({"label": format!("b{}", block)}));
+        instrs.push(json!({
+            "dest": var(0),
+            "op": "add",
+            "type": "int",
+            "args": [var(1 + rng.below(2)), var(1 + rng.below(8))]
+        }));
+        instrs.push(json!({
+            "dest": format!("t{}", block),
+            "op": "mul",
+            "type": "int",
+            "args": [var(0), var(1)]
+        }));
+        if block + 1 == blocks {
+            instrs.push(json!({"op": "print", "args": [var(0)]}));
+        } else {
+            let back = format!("b{}", rng.below(block + 1));
+            instrs.push(json!({
+                "op": "br",
+                "args": ["c"],
+                "labels": [format!("b{}", block + 1), back]
+            }));
+        }
+    }
+    json!({"functions": [{"name": "main", "instrs": instrs}]}).to_string()
+}
+
+fn instructions(program: &BBProgram) -> usize {
+    program
+        .func_index
+        .iter()
+        .flat_map(|function| &function.blocks)
+        .map(|block| block.instrs.len())
+        .sum()
+}
+
+// The time of the fastest of `repeat` runs of `f`, which returns the number of facts or
+// instructions it produced
+fn measure(repeat: usize, mut f: impl FnMut() -> usize) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut produced = 0;
+    for _ in 0..repeat {
+        let start = Instant::now();
+        produced = f();
+        best = best.min(start.elapsed());
+    }
+    (best, produced)
+}
+
+fn report(workload: &str, size: &str, input: usize, output: usize, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    println!(
+        "{:<20} {:<12} {:>8} in {:>9} out {:>9.3}s {:>11.0} in/s {:>11.0} out/s",
+        workload,
+        size,
+        input,
+        output,
+        seconds,
+        input as f64 / seconds,
+        output as f64 / seconds
+    );
+}
+
+fn bench_program(workload: &str, program: &Program, size: &str, facts: Vec<Fact>) {
+    let input = facts.len();
+    let (elapsed, output) = measure(3, || run_datalog(program, facts.clone()).unwrap().len());
+    report(workload, size, input, output, elapsed);
+}
+
+fn main() {
+    // cargo bench passes --bench, skip the flags
+    let args = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect::<Vec<_>>();
+    let sizes = args
+        .first()
+        .map_or(3, |n| n.parse().expect("sizes is a number"));
+    let only = args.get(1).map(|name| name.as_str());
+    let selected = |name: &str| only.is_none_or(|only| only == name);
+    let parse = |src: &str| parse_program(&mut Token::lexer(src)).unwrap();
+    let scales = (0..sizes).map(|i| 1u64 << i).collect::<Vec<_>>();
+
+    if selected("transitive_closure") {
+        let program = parse(TRANSITIVE_CLOSURE);
+        for scale in &scales {
+            let nodes = 100 * scale;
+            let size = format!("{} nodes", nodes);
+            bench_program(
+                "transitive_closure",
+                &program,
+                &size,
+                random_edges(nodes, 2),
+            );
+        }
+    }
+    if selected("same_generation") {
+        let program = parse(SAME_GENERATION);
+        for scale in &scales {
+            let nodes = 200 * scale;
+            let size = format!("{} nodes", nodes);
+            bench_program("same_generation", &program, &size, random_tree(nodes, 3));
+        }
+    }
+    if selected("triangles") {
+        let program = parse(TRIANGLES);
+        for scale in &scales {
+            let nodes = 500 * scale;
+            let size = format!("{} nodes", nodes);
+            bench_program("triangles", &program, &size, random_edges(nodes, 8));
+        }
+    }
+    if selected("liveness") {
+        for scale in &scales {
+            let blocks = 10 * scale;
+            let size = format!("{} blocks", blocks);
+            let src = synthetic_function(blocks);
+            // BBProgram can't be cloned, parse a program for every run before timing them
+            let mut programs = (0..3)
+                .map(|_| parse_bril(&src).unwrap())
+                .collect::<Vec<_>>();
+            let input = instructions(&programs[0]);
+            let (elapsed, output) = measure(3, || {
+                instructions(&perform_liveness_analysis(programs.pop().unwrap()))
+            });
+            report("liveness", &size, input, output, elapsed);
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use std::env;
+use std::path::PathBuf;
+
+// Compiles the sample programs to Rust, see src/lib.rs
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let samples = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("../samples");
+    for (program, name) in [
+        ("graph/simple_graph.dl", "simple_graph.rs"),
+        ("dataflow/liveness/liveness.dl", "liveness.rs"),
+        ("patterns/patterns.dl", "patterns.rs"),
+    ] {
+        datalogint::codegen::compile_file(samples.join(program), out_dir.join(name))
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+}
@@ -0,0 +1,130 @@
+// The sample programs compiled to Rust by datalogint::codegen in build.rs. The tests check
+// that the generated code derives the same facts as the interpreter on the sample inputs.
+
+pub mod simple_graph {
+    include!(concat!(env!("OUT_DIR"), "/simple_graph.rs"));
+}
+
+pub mod liveness {
+    include!(concat!(env!("OUT_DIR"), "/liveness.rs"));
+}
+
+pub mod patterns {
+    include!(concat!(env!("OUT_DIR"), "/patterns.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use datalogint::parse::{parse_fact_vector, parse_program, Fact, Program, Token};
+    use datalogint::parse_bril::{get_facts_from_bril_fn, parse_bril};
+    use datalogint::run_datalog;
+    use logos::Logos;
+    use std::fs;
+    use std::path::Path;
+
+    fn samples() -> &'static Path {
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../samples"))
+    }
+
+    fn read_program(path: &str) -> Program {
+        let src = fs::read_to_string(samples().join(path)).unwrap();
+        parse_program(&mut Token::lexer(&src)).unwrap()
+    }
+
+    fn pairs(facts: &[Fact], name: &str) -> Vec<(String, String)> {
+        let mut pairs = facts
+            .iter()
+            .filter(|fact| fact.name == name)
+            .map(|fact| (fact.params[0].clone(), fact.params[1].clone()))
+            .collect::<Vec<_>>();
+        pairs.sort();
+        pairs
+    }
+
+    fn singles(facts: &[Fact], name: &str) -> Vec<(String,)> {
+        let mut singles = facts
+            .iter()
+            .filter(|fact| fact.name == name)
+            .map(|fact| (fact.params[0].clone(),))
+            .collect::<Vec<_>>();
+        singles.sort();
+        singles
+    }
+
+    #[test]
+    fn test_simple_graph_matches_interpreter() {
+        let program = read_program("graph/simple_graph.dl");
+        let mut inputs = fs::read_dir(samples().join("graph"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "in"))
+            .collect::<Vec<_>>();
+        inputs.sort();
+        assert!(!inputs.is_empty());
+
+        for path in inputs {
+            let src = fs::read_to_string(&path).unwrap();
+            let facts = parse_fact_vector(&mut Token::lexer(&src)).unwrap();
+            let output = super::simple_graph::run(super::simple_graph::Input {
+                edge: pairs(&facts, "edge"),
+            });
+            let expected = run_datalog(&program, facts).unwrap().into_facts();
+            assert_eq!(
+                output.reachable,
+                pairs(&expected, "reachable"),
+                "{}",
+                path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn test_liveness_matches_interpreter() {
+        let program = read_program("dataflow/liveness/liveness.dl");
+        let mut inputs = fs::read_dir(samples().join("dataflow/liveness"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        inputs.sort();
+        assert!(!inputs.is_empty());
+
+        for path in inputs {
+            let bril_program = parse_bril(&fs::read_to_string(&path).unwrap()).unwrap();
+            for func in &bril_program.func_index {
+                let facts = get_facts_from_bril_fn(func);
+                let output = super::liveness::run(super::liveness::Input {
+                    successor: pairs(&facts, "successor"),
+                    undefined: pairs(&facts, "undefined"),
+                    var_used: pairs(&facts, "var_used"),
+                });
+                let expected = run_datalog(&program, facts).unwrap().into_facts();
+                assert_eq!(
+                    output.var_live,
+                    pairs(&expected, "var_live"),
+                    "{}",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    // repeated variables within a declaration and rules whose declarations share no variable
+    #[test]
+    fn test_patterns_match_interpreter() {
+        let program = read_program("patterns/patterns.dl");
+        let src = fs::read_to_string(samples().join("patterns/patterns.in")).unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer(&src)).unwrap();
+        let output = super::patterns::run(super::patterns::Input {
+            edge: pairs(&facts, "edge"),
+            node: singles(&facts, "node"),
+        });
+        let expected = run_datalog(&program, facts).unwrap().into_facts();
+        assert_eq!(output.reachable, pairs(&expected, "reachable"));
+        assert_eq!(output.cyclic, singles(&expected, "cyclic"));
+        assert_eq!(output.self_loop, singles(&expected, "self_loop"));
+        assert_eq!(output.pair, pairs(&expected, "pair"));
+        assert_eq!(output.cycle_to_loop, pairs(&expected, "cycle_to_loop"));
+        assert_eq!(output.pair.len(), 9);
+    }
+}
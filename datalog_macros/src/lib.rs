@@ -0,0 +1,125 @@
+use datalogint::codegen::generate_struct;
+use datalogint::parse::{parse_program, Token};
+use logos::Logos;
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+// Datalog programs inline in Rust:
+//
+//     datalog! {
+//         struct Reachability;
+//         .decl edge(x, y) .input;
+//         .decl reachable(x, y) .output;
+//         .rule reachable(x, y) :- 1 edge(x, y);
+//         .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+//     }
+//
+// expands to a struct (named Datalog without the `struct` line) with one Vec of String
+// tuples per relation and a `run(&mut self)` method that fills in the .output relations,
+// see datalogint::codegen::generate_struct. The tokens are turned back into source text and
+// parsed by datalogint::parse, so the syntax is the same as in .dl files except that there
+// are no `#` comments. Parse errors point at the offending token.
+#[proc_macro]
+pub fn datalog(input: TokenStream) -> TokenStream {
+    let mut tokens = input.into_iter().collect::<Vec<_>>();
+    let mut name = "Datalog".to_string();
+    if let [TokenTree::Ident(keyword), TokenTree::Ident(ident), TokenTree::Punct(semi), ..] =
+        &tokens[..]
+    {
+        if keyword.to_string() == "struct" && semi.as_char() == ';' {
+            name = ident.to_string();
+            tokens.drain(..3);
+        }
+    }
+
+    let mut source = Source::default();
+    source.push_tokens(tokens.into_iter().collect());
+
+    let mut lexer = Token::lexer(&source.text);
+    let program = match parse_program(&mut lexer) {
+        Ok(program) => program,
+        Err(e) => {
+            let span = source.span_at(lexer.span().start);
+            return compile_error(&format!("Error parsing rules: {}", e), span);
+        }
+    };
+    match generate_struct(&program, &name) {
+        Ok(code) => code.parse().unwrap(),
+        Err(e) => compile_error(&e, Span::call_site()),
+    }
+}
+
+// The macro input as text, with the span of the token at every byte range
+#[derive(Default)]
+struct Source {
+    text: String,
+    spans: Vec<(usize, usize, Span)>,
+    // the next token is written without a space before it, e.g. `decl` after `.`
+    glue: bool,
+}
+
+impl Source {
+    fn push(&mut self, text: &str, span: Span) {
+        if !self.glue && !self.text.is_empty() {
+            self.text.push(' ');
+        }
+        let start = self.text.len();
+        self.text.push_str(text);
+        self.spans.push((start, self.text.len(), span));
+        self.glue = false;
+    }
+
+    fn push_tokens(&mut self, stream: TokenStream) {
+        for tree in stream {
+            match tree {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(open, group.span_open());
+                    self.push_tokens(group.stream());
+                    self.push(close, group.span_close());
+                }
+                TokenTree::Punct(punct) => {
+                    self.push(&punct.as_char().to_string(), punct.span());
+                    // `.decl` and `:-` are single tokens for the lexer
+                    self.glue = punct.as_char() == '.' || punct.spacing() == Spacing::Joint;
+                }
+                TokenTree::Ident(ident) => self.push(&ident.to_string(), ident.span()),
+                TokenTree::Literal(literal) => self.push(&literal.to_string(), literal.span()),
+            }
+        }
+    }
+
+    // The span of the token at byte `offset`, or the last token when the error is at the
+    // end of the input
+    fn span_at(&self, offset: usize) -> Span {
+        self.spans
+            .iter()
+            .find(|(start, end, _)| *start <= offset && offset < *end)
+            .or(self.spans.last())
+            .map_or_else(Span::call_site, |(_, _, span)| *span)
+    }
+}
+
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut literal = Literal::string(message);
+    literal.set_span(span);
+    let mut args = Group::new(
+        Delimiter::Parenthesis,
+        TokenStream::from(TokenTree::Literal(literal)),
+    );
+    args.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut semi = Punct::new(';', Spacing::Alone);
+    semi.set_span(span);
+    TokenStream::from_iter([
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(args),
+        TokenTree::Punct(semi),
+    ])
+}
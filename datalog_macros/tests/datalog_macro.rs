@@ -0,0 +1,33 @@
+use datalog_macros::datalog;
+
+datalog! {
+    struct Reachability;
+    .decl edge(x, y) .input;
+    .decl reachable(x, y) .output;
+    .rule reachable(x, y) :- 1 edge(x, y);
+    .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+}
+
+#[test]
+fn test_inline_program() {
+    let mut program = Reachability::default();
+    program.edge = vec![
+        ("a".to_string(), "b".to_string()),
+        ("b".to_string(), "c".to_string()),
+    ];
+    program.run();
+    assert_eq!(
+        program.reachable,
+        vec![
+            ("a".to_string(), "b".to_string()),
+            ("a".to_string(), "c".to_string()),
+            ("b".to_string(), "c".to_string()),
+        ]
+    );
+}
+
+// Parse errors are compile errors at the offending token, see tests/ui/*.stderr
+#[test]
+fn test_parse_errors() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,17 @@
+use datalog_macros::datalog;
+
+// the body of the rule has no count
+datalog! {
+    .decl edge(x, y) .input;
+    .decl reachable(x, y) .output;
+    .rule reachable(x, y) :- edge(x, y);
+}
+
+// the count of an assertion is not a number
+datalog! {
+    struct Counted;
+    .decl edge(x, y) .input;
+    .assert count(edge) = many;
+}
+
+fn main() {}
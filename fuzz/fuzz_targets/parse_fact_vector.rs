@@ -0,0 +1,13 @@
+// Parsing arbitrary text as a fact file has to return an error instead of panicking.
+// Run with `cargo fuzz run parse_fact_vector` from the repository root.
+#![no_main]
+
+use datalogint::{parse::parse_fact_vector, Token};
+use libfuzzer_sys::fuzz_target;
+use logos::Logos;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(src) = std::str::from_utf8(data) {
+        let _ = parse_fact_vector(&mut Token::lexer(src));
+    }
+});
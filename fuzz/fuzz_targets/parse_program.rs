@@ -0,0 +1,13 @@
+// Parsing arbitrary text as a program has to return an error instead of panicking.
+// Run with `cargo fuzz run parse_program` from the repository root.
+#![no_main]
+
+use datalogint::{parse::parse_program, Token};
+use libfuzzer_sys::fuzz_target;
+use logos::Logos;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(src) = std::str::from_utf8(data) {
+        let _ = parse_program(&mut Token::lexer(src));
+    }
+});
@@ -0,0 +1,505 @@
+use crate::parse::{DeclKind, Program, Token};
+use crate::plan::{plan_program, JoinStep, Plan, RulePlan};
+use logos::Logos;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+// Code generation: turns a program into Rust source with the evaluation hard-coded.
+// Every relation becomes a HashSet of String tuples, every join step of the plan a nested
+// loop, and every join on a full relation a lookup in a HashMap index on the join columns
+// that is kept up to date as facts are added. The generated code has the items
+//
+//     pub struct Input { pub <input relation>: Vec<(String, ...)>, ... }
+//     pub struct Output { pub <output relation>: Vec<(String, ...)>, ... }
+//     pub fn run(input: Input) -> Output
+//
+// and is meant to be include!d from a build script's OUT_DIR, see compile_file.
+// generate_struct instead puts every relation in one struct with a run method, which is
+// what the datalog! macro of the datalog_macros crate expands to.
+
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+// Reads a .dl file and writes the generated code to `output`. Meant for build scripts:
+//
+//     datalogint::codegen::compile_file("analysis.dl", out_dir.join("analysis.rs"))?;
+//
+// and then `include!(concat!(env!("OUT_DIR"), "/analysis.rs"));` in the crate.
+pub fn compile_file(input: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<(), String> {
+    let input = input.as_ref();
+    println!("cargo:rerun-if-changed={}", input.display());
+    let src = std::fs::read_to_string(input)
+        .map_err(|e| format!("Error reading {}: {}", input.display(), e))?;
+    let program = crate::parse::parse_program(&mut Token::lexer(&src))
+        .map_err(|e| format!("Error parsing {}: {}", input.display(), e))?;
+    let code = generate_rust(&program)?;
+    std::fs::write(output.as_ref(), code)
+        .map_err(|e| format!("Error writing {}: {}", output.as_ref().display(), e))
+}
+
+// The shape of the generated code
+enum Target<'a> {
+    // Input and Output structs and a run function
+    Module,
+    // a struct with a field per relation, run fills in the .output ones
+    Struct(&'a str),
+}
+
+pub fn generate_rust(program: &Program) -> Result<String, String> {
+    generate(program, Target::Module)
+}
+
+// A struct `name` with one Vec of tuples per relation and a `run(&mut self)` method that
+// computes the .output relations from the .input ones
+pub fn generate_struct(program: &Program, name: &str) -> Result<String, String> {
+    generate(program, Target::Struct(name))
+}
+
+fn generate(program: &Program, target: Target) -> Result<String, String> {
+    if !program.constraints.is_empty() || !program.assertions.is_empty() {
+        return Err("Constraints and assertions are only checked by the interpreter".to_string());
+    }
+    if let Some(decl) = program.decls.iter().find(|decl| decl.eqrel) {
+        return Err(format!(
+            "Equivalence relation {} is only supported by the interpreter",
+            decl.name
+        ));
+    }
+    if let Some(decl) = program.decls.iter().find(|decl| decl.lattice.is_some()) {
+        return Err(format!(
+            "Lattice-valued relation {} is only supported by the interpreter",
+            decl.name
+        ));
+    }
+    let plan = plan_program(program)?;
+    let mut generator = Generator {
+        program,
+        arities: program
+            .decls
+            .iter()
+            .map(|decl| (decl.name.as_str(), decl.params.len()))
+            .collect(),
+        indexes: BTreeSet::new(),
+        out: String::new(),
+        indent: 0,
+    };
+    generator.generate(&plan, &target)?;
+    Ok(generator.out)
+}
+
+struct Generator<'a> {
+    program: &'a Program,
+    arities: HashMap<&'a str, usize>,
+    // (relation, columns) of every index the joins look up
+    indexes: BTreeSet<(String, Vec<usize>)>,
+    out: String,
+    indent: usize,
+}
+
+fn tuple_type(arity: usize) -> String {
+    match arity {
+        1 => "(String,)".to_string(),
+        _ => format!("({})", vec!["String"; arity].join(", ")),
+    }
+}
+
+fn tuple_expr(values: &[String]) -> String {
+    match values.len() {
+        1 => format!("({},)", values[0]),
+        _ => format!("({})", values.join(", ")),
+    }
+}
+
+fn field_name(relation: &str) -> Result<String, String> {
+    match relation {
+        "self" | "Self" | "super" | "crate" | "_" => Err(format!(
+            "Relation {} cannot be used as a Rust field name",
+            relation
+        )),
+        _ if KEYWORDS.contains(&relation) => Ok(format!("r#{}", relation)),
+        _ => Ok(relation.to_string()),
+    }
+}
+
+fn index_name(relation: &str, columns: &[usize]) -> String {
+    let columns = columns.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    format!("index_{}_{}", relation, columns.join("_"))
+}
+
+impl Generator<'_> {
+    fn line(&mut self, line: &str) {
+        if !line.is_empty() {
+            for _ in 0..self.indent {
+                self.out.push_str("    ");
+            }
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn open(&mut self, line: &str) {
+        self.line(line);
+        self.indent += 1;
+    }
+
+    fn close(&mut self, line: &str) {
+        self.indent -= 1;
+        self.line(line);
+    }
+
+    fn relation_type(&self, relation: &str) -> String {
+        tuple_type(self.arities[relation])
+    }
+
+    // Columns of the body declaration that are bound before the step, and so can be looked
+    // up in an index
+    fn key_columns(params: &[String], bound: &HashSet<String>) -> Vec<usize> {
+        (0..params.len())
+            .filter(|i| bound.contains(&params[*i]))
+            .collect()
+    }
+
+    // Finds the indexes used by the joins of a variant. With `naive`, every step reads the
+    // full relation.
+    fn collect_indexes(&mut self, rule_idx: usize, steps: &[JoinStep], naive: bool) {
+        let rule = &self.program.rules[rule_idx];
+        let mut bound = HashSet::new();
+        for step in steps {
+            let decl = &rule.body[step.body_idx];
+            let columns = Self::key_columns(&decl.params, &bound);
+            if (naive || !step.delta) && !columns.is_empty() {
+                self.indexes.insert((decl.name.clone(), columns));
+            }
+            bound.extend(decl.params.iter().cloned());
+        }
+    }
+
+    // Inserts the tuple in `value` into a relation and its indexes, running `on_new` if it
+    // was not there yet
+    fn insert(&mut self, relation: &str, value: &str, on_new: Option<&str>) {
+        self.open(&format!("if rel_{}.insert({}.clone()) {{", relation, value));
+        let indexes = self
+            .indexes
+            .iter()
+            .filter(|(name, _)| name == relation)
+            .cloned()
+            .collect::<Vec<_>>();
+        for (_, columns) in indexes {
+            let key = columns
+                .iter()
+                .map(|c| format!("{}.{}.clone()", value, c))
+                .collect::<Vec<_>>();
+            self.line(&format!(
+                "{}.entry({}).or_default().push({}.clone());",
+                index_name(relation, &columns),
+                tuple_expr(&key),
+                value
+            ));
+        }
+        if let Some(on_new) = on_new {
+            self.line(on_new);
+        }
+        self.close("}");
+    }
+
+    // The nested loops of one join order of a rule, pushing the head into new_<head>
+    fn join(&mut self, rule_idx: usize, steps: &[JoinStep], naive: bool) {
+        let rule = &self.program.rules[rule_idx];
+        let mut bound = HashSet::new();
+        let mut depth = 0;
+        for (step_idx, step) in steps.iter().enumerate() {
+            let decl = &rule.body[step.body_idx];
+            let tuple = format!("t{}", step_idx);
+            let columns = Self::key_columns(&decl.params, &bound);
+            let delta = step.delta && !naive;
+            if delta || columns.is_empty() {
+                let source = if delta { "delta" } else { "rel" };
+                self.open(&format!(
+                    "for {} in {}_{}.iter() {{",
+                    tuple, source, decl.name
+                ));
+                depth += 1;
+                for column in &columns {
+                    self.line(&format!(
+                        "if {}.{} != *v_{} {{ continue; }}",
+                        tuple, column, decl.params[*column]
+                    ));
+                }
+            } else {
+                let key = columns
+                    .iter()
+                    .map(|c| format!("v_{}.clone()", decl.params[*c]))
+                    .collect::<Vec<_>>();
+                self.open(&format!(
+                    "if let Some(matches) = {}.get(&{}) {{",
+                    index_name(&decl.name, &columns),
+                    tuple_expr(&key)
+                ));
+                self.open(&format!("for {} in matches {{", tuple));
+                depth += 2;
+            }
+            for (column, param) in decl.params.iter().enumerate() {
+                if columns.contains(&column) {
+                    continue;
+                }
+                if bound.contains(param) {
+                    // a parameter repeated within the declaration
+                    self.line(&format!(
+                        "if {}.{} != *v_{} {{ continue; }}",
+                        tuple, column, param
+                    ));
+                } else {
+                    self.line(&format!("let v_{} = &{}.{};", param, tuple, column));
+                    bound.insert(param.clone());
+                }
+            }
+        }
+        let head = rule
+            .head
+            .params
+            .iter()
+            .map(|param| format!("v_{}.clone()", param))
+            .collect::<Vec<_>>();
+        self.line(&format!(
+            "new_{}.push({});",
+            rule.head.name,
+            tuple_expr(&head)
+        ));
+        for _ in 0..depth {
+            self.close("}");
+        }
+    }
+
+    fn generate(&mut self, plan: &Plan, target: &Target) -> Result<(), String> {
+        let program = self.program;
+        for rule_plan in plan.rule_plans() {
+            self.collect_indexes(rule_plan.rule, &rule_plan.variants[0], true);
+            for steps in &rule_plan.variants {
+                self.collect_indexes(rule_plan.rule, steps, false);
+            }
+        }
+
+        self.line("// Generated by datalogint::codegen, do not edit.");
+        for rule in &program.rules {
+            self.line(&format!("// .rule {}", rule));
+        }
+        self.line("");
+        let structs = match target {
+            Target::Module => vec![
+                ("Input", vec![DeclKind::Input]),
+                ("Output", vec![DeclKind::Output]),
+            ],
+            Target::Struct(name) => vec![(*name, vec![DeclKind::Input, DeclKind::Output])],
+        };
+        for (struct_name, kinds) in structs {
+            self.line("#[derive(Debug, Clone, Default)]");
+            self.open(&format!("pub struct {} {{", struct_name));
+            for decl in program
+                .decls
+                .iter()
+                .filter(|decl| kinds.contains(&decl.kind))
+            {
+                let field = field_name(&decl.name)?;
+                let ty = self.relation_type(&decl.name);
+                self.line(&format!("// {}", decl));
+                self.line(&format!("pub {}: Vec<{}>,", field, ty));
+            }
+            self.close("}");
+            self.line("");
+        }
+
+        let input = match target {
+            Target::Module => {
+                self.line(
+                    "#[allow(unused_mut, unused_variables, unused_assignments, clippy::all)]",
+                );
+                self.open("pub fn run(input: Input) -> Output {");
+                "input"
+            }
+            Target::Struct(name) => {
+                self.open(&format!("impl {} {{", name));
+                self.line(
+                    "#[allow(unused_mut, unused_variables, unused_assignments, clippy::all)]",
+                );
+                self.open("pub fn run(&mut self) {");
+                "self"
+            }
+        };
+        for decl in &program.decls {
+            let ty = self.relation_type(&decl.name);
+            self.line(&format!(
+                "let mut rel_{}: std::collections::HashSet<{}> = std::collections::HashSet::new();",
+                decl.name, ty
+            ));
+        }
+        for (relation, columns) in self.indexes.clone() {
+            let key = tuple_type(columns.len());
+            let ty = self.relation_type(&relation);
+            self.line(&format!(
+                "let mut {}: std::collections::HashMap<{}, Vec<{}>> = std::collections::HashMap::new();",
+                index_name(&relation, &columns),
+                key,
+                ty
+            ));
+        }
+        for decl in program
+            .decls
+            .iter()
+            .filter(|decl| decl.kind == DeclKind::Input)
+        {
+            let source = match target {
+                Target::Module => format!("{}.{}", input, field_name(&decl.name)?),
+                Target::Struct(_) => {
+                    format!("{}.{}.iter().cloned()", input, field_name(&decl.name)?)
+                }
+            };
+            self.open(&format!("for fact in {} {{", source));
+            self.insert(&decl.name, "fact", None);
+            self.close("}");
+        }
+
+        for (stratum_idx, stratum) in plan.strata.iter().enumerate() {
+            self.line("");
+            self.line(&format!(
+                "// stratum {}{}: {}",
+                stratum_idx,
+                if stratum.recursive {
+                    " (recursive)"
+                } else {
+                    ""
+                },
+                stratum.relations.join(", ")
+            ));
+            self.open("{");
+            for relation in &stratum.relations {
+                let ty = self.relation_type(relation);
+                self.line(&format!(
+                    "let mut delta_{}: Vec<{}> = Vec::new();",
+                    relation, ty
+                ));
+            }
+            self.line("let mut first = true;");
+            self.open("loop {");
+            for relation in &stratum.relations {
+                let ty = self.relation_type(relation);
+                self.line(&format!(
+                    "let mut new_{}: Vec<{}> = Vec::new();",
+                    relation, ty
+                ));
+            }
+            // the first round sees every fact, so each rule runs once on the full relations
+            self.open("if first {");
+            for rule_plan in &stratum.rules {
+                self.rule_comment(rule_plan);
+                self.join(rule_plan.rule, &rule_plan.variants[0], true);
+            }
+            if stratum.recursive {
+                // later rounds only join the facts that are new since the last one
+                self.indent -= 1;
+                self.open("} else {");
+                for rule_plan in &stratum.rules {
+                    let rule = &program.rules[rule_plan.rule];
+                    for steps in &rule_plan.variants {
+                        let delta = &rule.body[steps[0].body_idx].name;
+                        if !stratum.relations.contains(delta) {
+                            continue;
+                        }
+                        self.rule_comment(rule_plan);
+                        self.join(rule_plan.rule, steps, false);
+                    }
+                }
+            }
+            self.close("}");
+            self.line("first = false;");
+            self.line("let mut changed = false;");
+            for relation in &stratum.relations {
+                self.line(&format!("delta_{}.clear();", relation));
+                self.open(&format!("for fact in new_{} {{", relation));
+                let on_new = format!("delta_{}.push(fact);", relation);
+                self.insert(relation, "fact", Some(&on_new));
+                self.close("}");
+                self.line(&format!(
+                    "changed = changed || !delta_{}.is_empty();",
+                    relation
+                ));
+            }
+            if stratum.recursive {
+                self.open("if !changed {");
+                self.line("break;");
+                self.close("}");
+            } else {
+                self.line("break;");
+            }
+            self.close("}");
+            self.close("}");
+        }
+
+        self.line("");
+        if let Target::Module = target {
+            self.open("Output {");
+        }
+        for decl in program
+            .decls
+            .iter()
+            .filter(|decl| decl.kind == DeclKind::Output)
+        {
+            match target {
+                Target::Module => self.open(&format!("{}: {{", field_name(&decl.name)?)),
+                Target::Struct(_) => self.open(&format!("self.{} = {{", field_name(&decl.name)?)),
+            }
+            self.line(&format!(
+                "let mut facts = rel_{}.into_iter().collect::<Vec<_>>();",
+                decl.name
+            ));
+            self.line("facts.sort();");
+            self.line("facts");
+            match target {
+                Target::Module => self.close("},"),
+                Target::Struct(_) => self.close("};"),
+            }
+        }
+        // closes the Output literal and run, or run and the impl block
+        self.close("}");
+        self.close("}");
+        Ok(())
+    }
+
+    fn rule_comment(&mut self, rule_plan: &RulePlan) {
+        let text = format!(
+            "// rule {}: {}",
+            rule_plan.rule, self.program.rules[rule_plan.rule]
+        );
+        self.line(&text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate_rust;
+    use crate::parse::{parse_program, Token};
+    use logos::Logos;
+
+    #[test]
+    fn test_generate_reachable() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl reachable(x, y) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            "#,
+        ))
+        .unwrap();
+        let code = generate_rust(&program).unwrap();
+        assert!(code.contains("pub edge: Vec<(String, String)>,"));
+        assert!(code.contains("pub reachable: Vec<(String, String)>,"));
+        // edge(y, z) is joined on y through an index, reachable(x, y) reads the delta
+        assert!(code.contains("index_edge_0.get(&(v_y.clone(),))"));
+        assert!(code.contains("for t0 in delta_reachable.iter()"));
+    }
+}
@@ -0,0 +1,162 @@
+use crate::database::Database;
+use crate::implem::evaluate_variant;
+use crate::parse::Program;
+use crate::plan::Plan;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Integrity constraints and assertions, checked once a program is evaluated. A constraint
+// `:- 2 var_live(x, v), undefined(x, v);` is violated by every binding of its parameters
+// that makes its body hold, an assertion `.assert count(reachable) = 12;` when the relation
+// has a different number of facts.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    Constraint {
+        constraint: String,
+        // sorted, every binding maps all parameters of the body
+        bindings: Vec<BTreeMap<String, String>>,
+    },
+    Assertion {
+        assertion: String,
+        count: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Constraint {
+                constraint,
+                bindings,
+            } => {
+                let bindings = bindings
+                    .iter()
+                    .map(|binding| {
+                        binding
+                            .iter()
+                            .map(|(param, value)| format!("{} = {}", param, value))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, "{} holds for {}", constraint, bindings.join("; "))
+            }
+            Violation::Assertion { assertion, count } => {
+                write!(f, "{} does not hold, there are {} facts", assertion, count)
+            }
+        }
+    }
+}
+
+// The violated constraints and assertions of `program` on its evaluated `database`, in the
+// order they appear in the program
+pub(crate) fn check_constraints(
+    program: &Program,
+    plan: &Plan,
+    database: &Database,
+) -> Vec<Violation> {
+    let mut violations = vec![];
+    for (constraint, steps) in program.constraints.iter().zip(&plan.constraints) {
+        // every step reads the full database, external relations included
+        let result = evaluate_variant(
+            &constraint.body,
+            steps,
+            &plan.externals,
+            database,
+            database,
+            true,
+            (0, 1),
+        );
+        let Some(mapping) = result.and_then(|result| result.mapping) else {
+            continue;
+        };
+        let mut bindings = mapping.parameter_maps.into_iter().collect::<Vec<_>>();
+        if bindings.is_empty() {
+            continue;
+        }
+        bindings.sort();
+        violations.push(Violation::Constraint {
+            constraint: constraint.to_string(),
+            bindings,
+        });
+    }
+    for assertion in &program.assertions {
+        let count = database
+            .tables
+            .get(&assertion.relation)
+            .map_or(0, |table| table.len());
+        if count != assertion.count {
+            violations.push(Violation::Assertion {
+                assertion: assertion.to_string(),
+                count,
+            });
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Violation;
+    use crate::implem::{run_datalog, RunError};
+    use crate::parse::{parse_fact_vector, parse_program, Token};
+    use logos::Logos;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_constraints_and_assertions() {
+        let src = r#"
+            .decl edge(x, y) .input;
+            .decl reachable(x, y) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            :- 1 reachable(x, x);
+            .assert count(reachable) = 3;
+        "#;
+        let program = parse_program(&mut Token::lexer(src)).unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer("2 edge(a, b); edge(b, c);")).unwrap();
+        assert_eq!(run_datalog(&program, facts).unwrap().len(), 3);
+
+        let facts =
+            parse_fact_vector(&mut Token::lexer("3 edge(a, b); edge(b, c); edge(c, a);")).unwrap();
+        let Err(RunError::ConstraintViolated {
+            violations,
+            database,
+        }) = run_datalog(&program, facts)
+        else {
+            panic!("expected violations");
+        };
+        // the output relations are still returned
+        assert_eq!(database.len(), 9);
+        let binding = |x: &str| BTreeMap::from([("x".to_string(), x.to_string())]);
+        assert_eq!(
+            violations,
+            vec![
+                Violation::Constraint {
+                    constraint: ":- reachable(x, x)".to_string(),
+                    bindings: vec![binding("a"), binding("b"), binding("c")],
+                },
+                Violation::Assertion {
+                    assertion: "count(reachable) = 3".to_string(),
+                    count: 9,
+                },
+            ]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            ":- reachable(x, x) holds for x = a; x = b; x = c"
+        );
+
+        for src in [
+            ".decl edge(x, y) .input; :- 2 edge(x, y), path(y, x);",
+            ".decl edge(x, y) .input; .assert count(path) = 0;",
+        ] {
+            let undeclared = parse_program(&mut Token::lexer(src)).unwrap();
+            assert!(matches!(
+                run_datalog(&undeclared, vec![]),
+                Err(RunError::Invalid(_))
+            ));
+        }
+    }
+}
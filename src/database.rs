@@ -0,0 +1,1076 @@
+use crate::external::Externals;
+use crate::lattice::{LatticeOps, Value};
+use crate::parse::{Fact, Program};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+// a fact is characterized by a mapping from relation name to a set of parameters
+// when we run datalog, we are "joining" the tables in the rules on a specified set of keys
+// A table holds the facts of one relation. They are stored as sorted, deduplicated runs of
+// parameters, column by column (like the arrangements of differential dataflow). New facts
+// are added in batches: a batch is sorted, deduplicated and stripped of the facts already in
+// the table, becomes a new run, and is merged with the run before it while it is at least
+// half that size, so a table of n facts has O(log n) runs.
+#[derive(Debug, Clone)]
+pub(crate) struct Table {
+    pub(crate) name: String,
+    arity: usize,
+    // oldest and largest first
+    runs: Vec<Run>,
+    len: usize,
+    // set by Database::sort when a column only holds integers: the indices of the rows in
+    // numeric order, which differs from the order of the rows
+    order: Option<Vec<usize>>,
+    // set for an .eqrel relation, which keeps its classes instead of runs
+    eqrel: Option<EqRel>,
+    // set for a lattice-valued relation, which keeps a value per key instead of runs
+    lattice: Option<LatticeValues>,
+}
+
+// The classes of an equivalence relation, as a union-find over its elements. The relation
+// holds every pair of elements in the same class, but they are never materialized: a scan
+// goes through the class of every element, a lookup by the first element through its
+// class, and a pair is checked by comparing roots. Unions are by size and point every
+// element of the smaller class at the new root, so paths are always compressed to a single
+// step and an element moves to another class O(log n) times.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EqRel {
+    ids: HashMap<String, usize>,
+    elements: Vec<String>,
+    root: Vec<usize>,
+    // the elements of the class of every root, empty for the other elements
+    members: Vec<Vec<usize>>,
+    // the elements in the order their pairs are enumerated: insertion order, or sorted
+    // along with every class by Database::sort
+    order: Vec<usize>,
+}
+
+// The values of a lattice-valued relation: per key (the parameters but the last), the join
+// of every value inserted for it, parsed and as it is printed. The map is sorted by key, so
+// the facts are read from it like from a single run.
+#[derive(Debug, Clone)]
+struct LatticeValues {
+    lattice: LatticeOps,
+    values: BTreeMap<Vec<String>, (Value, String)>,
+}
+
+impl LatticeValues {
+    fn value(&self, key: &[String]) -> &Value {
+        self.values
+            .get(key)
+            .map_or(self.lattice.bottom(), |(value, _)| value)
+    }
+
+    // A value of a fact that got into the database, so it was checked against the lattice
+    // (see Database::check_lattice_values)
+    fn parse(&self, value: &str) -> Value {
+        self.lattice
+            .parse(value)
+            .unwrap_or_else(|e| panic!("unchecked value: {}", e))
+    }
+
+    // Whether `value` is below the value of `key`, which is bottom for a new key
+    fn subsumed(&self, key: &[String], value: &str) -> bool {
+        self.lattice.leq(&self.parse(value), self.value(key))
+    }
+
+    fn rows(&self, range: Range<usize>) -> impl Iterator<Item = Row<'_>> {
+        self.values
+            .iter()
+            .skip(range.start)
+            .take(range.len())
+            .map(|(key, (_, value))| Row::Lattice(key, value))
+    }
+
+    // The facts whose first parameters are equal to `prefix`, which is at most a key long
+    fn prefix<'a>(&'a self, prefix: &[&str]) -> impl Iterator<Item = Row<'a>> + 'a {
+        let start = prefix.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        self.values
+            .range(start.clone()..)
+            .take_while(move |(key, _)| key[..start.len()] == start[..])
+            .map(|(key, (_, value))| Row::Lattice(key, value))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Run {
+    columns: Vec<Vec<String>>,
+    len: usize,
+}
+
+// One fact of a table, without copying its parameters: a row of a run, or a pair of an
+// equivalence relation
+#[derive(Clone, Copy)]
+pub(crate) enum Row<'a> {
+    Run(&'a Run, usize),
+    Pair(&'a str, &'a str),
+    // a key of a lattice-valued relation and its value
+    Lattice(&'a [String], &'a str),
+}
+
+impl<'a> Row<'a> {
+    pub(crate) fn get(&self, column: usize) -> &'a str {
+        match self {
+            Row::Run(run, idx) => &run.columns[column][*idx],
+            Row::Pair(x, y) => [*x, *y][column],
+            Row::Lattice(key, value) => key.get(column).map_or(value, |param| param),
+        }
+    }
+
+    fn arity(&self) -> usize {
+        match self {
+            Row::Run(run, _) => run.columns.len(),
+            Row::Pair(..) => 2,
+            Row::Lattice(key, _) => key.len() + 1,
+        }
+    }
+
+    pub(crate) fn params(&self) -> Vec<String> {
+        (0..self.arity())
+            .map(|column| self.get(column).to_string())
+            .collect()
+    }
+}
+
+// A fact of a Database, borrowed from the columns of its relation
+#[derive(Clone, Copy)]
+pub struct FactRef<'a> {
+    name: &'a str,
+    row: Row<'a>,
+}
+
+impl<'a> FactRef<'a> {
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    // The parameter at `column`, which has to be below the arity of the relation
+    pub fn param(&self, column: usize) -> &'a str {
+        self.row.get(column)
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = &'a str> {
+        let row = self.row;
+        (0..row.arity()).map(move |column| row.get(column))
+    }
+
+    pub fn to_fact(self) -> Fact {
+        Fact {
+            name: self.name.to_string(),
+            params: self.row.params(),
+        }
+    }
+}
+
+impl fmt::Display for FactRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}({})",
+            self.name,
+            self.params().collect::<Vec<_>>().join(", ")
+        )
+    }
+}
+
+// The first index in 0..len for which `pred` is false, `pred` being true before it
+fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut lo, mut hi) = (0, len);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if pred(mid) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+impl Run {
+    fn from_sorted(arity: usize, tuples: Vec<Vec<String>>) -> Run {
+        let mut columns = vec![Vec::with_capacity(tuples.len()); arity];
+        let len = tuples.len();
+        for tuple in tuples {
+            for (column, param) in columns.iter_mut().zip(tuple) {
+                column.push(param);
+            }
+        }
+        Run { columns, len }
+    }
+
+    // Compares the first params.len() columns of row `idx` with `params`
+    fn compare(&self, idx: usize, params: &[&str]) -> Ordering {
+        for (column, param) in self.columns.iter().zip(params) {
+            let ordering = column[idx].as_str().cmp(param);
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+
+    // The rows whose first prefix.len() columns are equal to `prefix`
+    fn prefix_range(&self, prefix: &[&str]) -> Range<usize> {
+        let start = partition_point(self.len, |idx| self.compare(idx, prefix) == Ordering::Less);
+        let end = partition_point(self.len, |idx| {
+            self.compare(idx, prefix) != Ordering::Greater
+        });
+        start..end
+    }
+
+    fn merge(&self, other: &Run) -> Run {
+        let arity = self.columns.len();
+        let mut merged = Run {
+            columns: vec![Vec::with_capacity(self.len + other.len); arity],
+            len: self.len + other.len,
+        };
+        let (mut i, mut j) = (0, 0);
+        while i < self.len || j < other.len {
+            let take_self = j == other.len
+                || (i < self.len
+                    && self
+                        .columns
+                        .iter()
+                        .zip(&other.columns)
+                        .map(|(a, b)| a[i].cmp(&b[j]))
+                        .find(|ordering| *ordering != Ordering::Equal)
+                        == Some(Ordering::Less));
+            let (run, idx) = if take_self {
+                (self, &mut i)
+            } else {
+                (other, &mut j)
+            };
+            for (column, values) in merged.columns.iter_mut().zip(&run.columns) {
+                column.push(values[*idx].clone());
+            }
+            *idx += 1;
+        }
+        merged
+    }
+}
+
+impl EqRel {
+    pub(crate) fn same_class(&self, a: &str, b: &str) -> bool {
+        match (self.ids.get(a), self.ids.get(b)) {
+            (Some(a), Some(b)) => self.root[*a] == self.root[*b],
+            _ => false,
+        }
+    }
+
+    // The id of `element`, adding it in a class of its own (and so the pair of it with
+    // itself) if it is new
+    fn add(&mut self, element: &str, changed: &mut Vec<Vec<String>>) -> usize {
+        if let Some(id) = self.ids.get(element) {
+            return *id;
+        }
+        let id = self.elements.len();
+        self.ids.insert(element.to_string(), id);
+        self.elements.push(element.to_string());
+        self.root.push(id);
+        self.members.push(vec![id]);
+        self.order.push(id);
+        changed.push(vec![element.to_string(), element.to_string()]);
+        id
+    }
+
+    // Merges the classes of a and b. Every element that moves to the other class is added
+    // to `changed` as the pair of it and its new root, which stands for all of its new
+    // pairs (see Database::delta_rows).
+    pub(crate) fn union(&mut self, a: &str, b: &str, changed: &mut Vec<Vec<String>>) {
+        let a = self.add(a, changed);
+        let b = self.add(b, changed);
+        let (mut root, mut other) = (self.root[a], self.root[b]);
+        if root == other {
+            return;
+        }
+        if self.members[root].len() < self.members[other].len() {
+            std::mem::swap(&mut root, &mut other);
+        }
+        let moved = std::mem::take(&mut self.members[other]);
+        for id in &moved {
+            self.root[*id] = root;
+            changed.push(vec![
+                self.elements[*id].clone(),
+                self.elements[root].clone(),
+            ]);
+        }
+        self.members[root].extend(moved);
+    }
+
+    fn len(&self) -> usize {
+        self.members
+            .iter()
+            .map(|class| class.len() * class.len())
+            .sum()
+    }
+
+    // The pairs of the element `x` with every element of its class
+    fn pairs(&self, x: usize) -> impl Iterator<Item = Row<'_>> {
+        self.members[self.root[x]]
+            .iter()
+            .map(move |y| Row::Pair(&self.elements[x], &self.elements[*y]))
+    }
+
+    // The pairs at `range` of the pairs of every element in order
+    fn rows(&self, range: Range<usize>) -> impl Iterator<Item = Row<'_>> {
+        let mut offset = 0;
+        self.order.iter().flat_map(move |x| {
+            let size = self.members[self.root[*x]].len();
+            let start = range.start.clamp(offset, offset + size) - offset;
+            let end = range.end.clamp(offset, offset + size) - offset;
+            offset += size;
+            self.pairs(*x).skip(start).take(end - start)
+        })
+    }
+
+    // The pairs whose first elements are equal to `prefix`
+    fn prefix<'a>(&'a self, prefix: &[&str]) -> Box<dyn Iterator<Item = Row<'a>> + 'a> {
+        let ids = prefix
+            .iter()
+            .map(|element| self.ids.get(*element).copied())
+            .collect::<Option<Vec<_>>>();
+        match ids.as_deref() {
+            Some([]) => Box::new(self.rows(0..self.len())),
+            Some([x]) => Box::new(self.pairs(*x)),
+            Some([x, y]) if self.root[*x] == self.root[*y] => Box::new(std::iter::once(Row::Pair(
+                &self.elements[*x],
+                &self.elements[*y],
+            ))),
+            _ => Box::new(std::iter::empty()),
+        }
+    }
+
+    // Sorts the elements and every class, numerically if every element is an integer
+    fn sort(&mut self) {
+        let numeric = self
+            .elements
+            .iter()
+            .all(|element| element.parse::<i64>().is_ok());
+        let elements = &self.elements;
+        let compare = |a: &usize, b: &usize| {
+            let (a, b) = (&elements[*a], &elements[*b]);
+            if numeric {
+                a.parse::<i64>().unwrap().cmp(&b.parse::<i64>().unwrap())
+            } else {
+                a.cmp(b)
+            }
+        };
+        self.order.sort_by(compare);
+        for class in &mut self.members {
+            class.sort_by(compare);
+        }
+    }
+}
+
+impl Table {
+    pub(crate) fn new(name: &str, arity: usize) -> Table {
+        Table {
+            name: name.to_string(),
+            arity,
+            runs: vec![],
+            len: 0,
+            order: None,
+            eqrel: None,
+            lattice: None,
+        }
+    }
+
+    fn new_eqrel(name: &str) -> Table {
+        Table {
+            eqrel: Some(EqRel::default()),
+            ..Table::new(name, 2)
+        }
+    }
+
+    fn new_lattice(name: &str, arity: usize, lattice: &LatticeOps) -> Table {
+        Table {
+            lattice: Some(LatticeValues {
+                lattice: lattice.clone(),
+                values: BTreeMap::new(),
+            }),
+            ..Table::new(name, arity)
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn contains(&self, params: &[String]) -> bool {
+        if params.len() != self.arity {
+            return false;
+        }
+        if let Some(eqrel) = &self.eqrel {
+            return eqrel.same_class(&params[0], &params[1]);
+        }
+        if let Some(lattice) = &self.lattice {
+            let (value, key) = params.split_last().unwrap();
+            return lattice
+                .values
+                .get(key)
+                .is_some_and(|(_, current)| current == value);
+        }
+        let params = params.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+        self.runs
+            .iter()
+            .any(|run| !run.prefix_range(&params).is_empty())
+    }
+
+    // Adds a batch of parameters, returning the ones that were not in the table yet. For an
+    // .eqrel relation these are the pairs of every element that changed class and its new
+    // root (see EqRel::union), for a lattice-valued relation the keys whose value went up,
+    // with their new value.
+    pub(crate) fn insert(&mut self, mut tuples: Vec<Vec<String>>) -> Vec<Vec<String>> {
+        if let Some(lattice) = &mut self.lattice {
+            let mut changed = BTreeSet::new();
+            for mut key in tuples {
+                let value = lattice.parse(&key.pop().unwrap());
+                let current = lattice.value(&key);
+                if lattice.lattice.leq(&value, current) {
+                    continue;
+                }
+                let joined = lattice.lattice.join(current, &value);
+                let shown = lattice.lattice.show(&joined);
+                lattice.values.insert(key.clone(), (joined, shown));
+                changed.insert(key);
+            }
+            if !changed.is_empty() {
+                self.len = lattice.values.len();
+                self.order = None;
+            }
+            return changed
+                .into_iter()
+                .map(|mut key| {
+                    key.push(lattice.values[&key].1.clone());
+                    key
+                })
+                .collect();
+        }
+        if let Some(eqrel) = &mut self.eqrel {
+            let mut changed = vec![];
+            for tuple in &tuples {
+                eqrel.union(&tuple[0], &tuple[1], &mut changed);
+            }
+            self.len = eqrel.len();
+            changed.sort();
+            changed.dedup();
+            return changed;
+        }
+        tuples.sort();
+        tuples.dedup();
+        tuples.retain(|tuple| !self.contains(tuple));
+        if tuples.is_empty() {
+            return tuples;
+        }
+        self.len += tuples.len();
+        self.order = None;
+        self.runs.push(Run::from_sorted(self.arity, tuples.clone()));
+        while let [.., previous, last] = &self.runs[..] {
+            if last.len * 2 < previous.len {
+                break;
+            }
+            let merged = previous.merge(last);
+            self.runs.truncate(self.runs.len() - 2);
+            self.runs.push(merged);
+        }
+        tuples
+    }
+
+    // Merges all runs into one
+    fn compact(&mut self) {
+        if let Some((first, rest)) = self.runs.split_first().filter(|(_, rest)| !rest.is_empty()) {
+            let merged = rest
+                .iter()
+                .fold(first.clone(), |merged, run| merged.merge(run));
+            self.runs = vec![merged];
+        }
+    }
+
+    // The rows at `range` of the concatenated runs
+    pub(crate) fn rows(&self, range: Range<usize>) -> Box<dyn Iterator<Item = Row<'_>> + '_> {
+        if let Some(eqrel) = &self.eqrel {
+            return Box::new(eqrel.rows(range));
+        }
+        if let Some(lattice) = &self.lattice {
+            return Box::new(lattice.rows(range));
+        }
+        let mut offset = 0;
+        Box::new(self.runs.iter().flat_map(move |run| {
+            let start = range.start.clamp(offset, offset + run.len) - offset;
+            let end = range.end.clamp(offset, offset + run.len) - offset;
+            offset += run.len;
+            (start..end).map(move |idx| Row::Run(run, idx))
+        }))
+    }
+
+    // The rows whose first columns are equal to `prefix`, found by binary search in each run
+    // (up front, so that the rows don't borrow the prefix), through the classes of an
+    // .eqrel relation, or by key for a lattice-valued relation
+    pub(crate) fn prefix<'a>(&'a self, prefix: &[&str]) -> Box<dyn Iterator<Item = Row<'a>> + 'a> {
+        if let Some(eqrel) = &self.eqrel {
+            return eqrel.prefix(prefix);
+        }
+        if let Some(lattice) = &self.lattice {
+            let key_len = self.arity - 1;
+            let rows = lattice.prefix(&prefix[..prefix.len().min(key_len)]);
+            return match prefix.get(key_len) {
+                Some(value) => {
+                    let value = value.to_string();
+                    Box::new(rows.filter(move |row| row.get(key_len) == value))
+                }
+                None => Box::new(rows),
+            };
+        }
+        let ranges = self
+            .runs
+            .iter()
+            .map(|run| (run, run.prefix_range(prefix)))
+            .collect::<Vec<_>>();
+        Box::new(
+            ranges
+                .into_iter()
+                .flat_map(|(run, range)| range.map(move |idx| Row::Run(run, idx))),
+        )
+    }
+
+    // The facts in output order: sorted if Database::sort was called, run by run otherwise
+    fn facts(&self) -> impl Iterator<Item = FactRef<'_>> {
+        let rows = match &self.order {
+            Some(order) => {
+                let rows = self.rows(0..self.len).collect::<Vec<_>>();
+                Box::new(order.iter().map(move |idx| rows[*idx]))
+            }
+            None => self.rows(0..self.len),
+        };
+        rows.map(|row| FactRef {
+            name: &self.name,
+            row,
+        })
+    }
+
+    fn tuples(&self) -> Vec<Vec<String>> {
+        self.rows(0..self.len).map(|row| row.params()).collect()
+    }
+}
+
+// Facts grouped by relation. Used during evaluation, and returned by run_datalog with the
+// .output relations of the program (or every relation with RunOptions::all_relations).
+#[derive(Debug, Clone, Default)]
+pub struct Database {
+    pub(crate) tables: HashMap<String, Table>,
+    // the relations stored as equivalence relations once they get facts
+    eqrels: HashSet<String>,
+    // the lattice-valued relations and their lattice
+    lattices: HashMap<String, LatticeOps>,
+}
+
+impl Database {
+    pub fn new() -> Database {
+        Database {
+            tables: HashMap::new(),
+            eqrels: HashSet::new(),
+            lattices: HashMap::new(),
+        }
+    }
+
+    // An empty database for `program`, which stores its .eqrel relations as EqRel and its
+    // lattice-valued relations as LatticeValues, with the lattices registered in `externals`
+    pub(crate) fn for_program(program: &Program, externals: &Externals) -> Database {
+        Database {
+            tables: HashMap::new(),
+            eqrels: program
+                .decls
+                .iter()
+                .filter(|decl| decl.eqrel)
+                .map(|decl| decl.name.clone())
+                .collect(),
+            lattices: program
+                .decls
+                .iter()
+                .filter_map(|decl| {
+                    let lattice = externals.get_lattice(decl.lattice.as_ref()?)?;
+                    Some((decl.name.clone(), lattice.clone()))
+                })
+                .collect(),
+        }
+    }
+
+    // Whether inserting facts can add other facts than the inserted ones, or change them:
+    // the implied pairs of .eqrel relations and the joined values of lattice relations
+    pub(crate) fn merges_facts(&self) -> bool {
+        !self.eqrels.is_empty() || !self.lattices.is_empty()
+    }
+
+    pub(crate) fn is_eqrel(&self, name: &str) -> bool {
+        self.eqrels.contains(name)
+    }
+
+    // The rows at `range` of `table`, a table of a delta of this database. The delta of an
+    // .eqrel relation only holds the pair of every element that changed class and its root
+    // (see EqRel::union), which stands for the pairs of the element with every element of
+    // its class, both ways round, so these are read from the classes in this database.
+    pub(crate) fn delta_rows<'a>(
+        &'a self,
+        table: &'a Table,
+        range: Range<usize>,
+    ) -> Box<dyn Iterator<Item = Row<'a>> + 'a> {
+        let eqrel = self
+            .tables
+            .get(&table.name)
+            .and_then(|full| full.eqrel.as_ref());
+        match eqrel {
+            Some(eqrel) if table.eqrel.is_none() => {
+                Box::new(table.rows(range).flat_map(move |row| {
+                    eqrel
+                        .pairs(eqrel.ids[row.get(0)])
+                        .flat_map(|pair| [pair, Row::Pair(pair.get(1), pair.get(0))])
+                }))
+            }
+            _ => table.rows(range),
+        }
+    }
+
+    // Checks that the facts of lattice-valued relations have a value of their lattice
+    pub(crate) fn check_lattice_values<'a>(
+        &self,
+        facts: impl IntoIterator<Item = &'a Fact>,
+    ) -> Result<(), String> {
+        if self.lattices.is_empty() {
+            return Ok(());
+        }
+        for fact in facts {
+            if let (Some(lattice), Some(value)) =
+                (self.lattices.get(&fact.name), fact.params.last())
+            {
+                lattice
+                    .check(value)
+                    .map_err(|e| format!("{} in {}", e, fact))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_facts(facts: Vec<Fact>) -> Database {
+        let mut database = Database::new();
+        extend_database(&mut database, &facts);
+        database
+    }
+
+    // The facts of relation `name`, none if the relation has none
+    pub fn relation(&self, name: &str) -> impl Iterator<Item = FactRef<'_>> {
+        self.tables.get(name).into_iter().flat_map(Table::facts)
+    }
+
+    // The names of the relations that have at least one fact, sorted
+    pub fn relations(&self) -> Vec<&str> {
+        let mut names = self
+            .tables
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    // The facts of relation `name` that match `pattern`, where Some(value) has to be equal
+    // to the parameter at that position and None matches anything, e.g.
+    // `db.query("var_live", [Some("b_instr_0"), None])`. The leading bound parameters are
+    // looked up by binary search.
+    // A pattern with the wrong number of parameters matches nothing.
+    pub fn query<'a, 'p>(
+        &'a self,
+        name: &str,
+        pattern: impl AsRef<[Option<&'p str>]>,
+    ) -> impl Iterator<Item = FactRef<'a>> {
+        let pattern = pattern
+            .as_ref()
+            .iter()
+            .map(|value| value.map(|value| value.to_string()))
+            .collect::<Vec<_>>();
+        let table = self
+            .tables
+            .get(name)
+            .filter(|table| table.arity == pattern.len());
+        let prefix = pattern
+            .iter()
+            .map_while(|value| value.as_deref())
+            .collect::<Vec<_>>();
+        let relation = table.map_or("", |table| table.name.as_str());
+        table
+            .map(|table| table.prefix(&prefix))
+            .into_iter()
+            .flatten()
+            .filter(move |row| {
+                pattern.iter().enumerate().all(|(column, value)| {
+                    value.as_ref().is_none_or(|value| row.get(column) == value)
+                })
+            })
+            .map(move |row| FactRef {
+                name: relation,
+                row,
+            })
+    }
+
+    // Whether inserting `fact` would add nothing: it is in the database, or it is a fact of a
+    // lattice-valued relation whose value is below the one of its key
+    pub(crate) fn subsumes(&self, fact: &Fact) -> bool {
+        match self.tables.get(&fact.name) {
+            Some(Table {
+                lattice: Some(lattice),
+                arity,
+                ..
+            }) if fact.params.len() == *arity => {
+                let (value, key) = fact.params.split_last().unwrap();
+                lattice.subsumed(key, value)
+            }
+            Some(table) => table.contains(&fact.params),
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, fact: &Fact) -> bool {
+        self.tables
+            .get(&fact.name)
+            .is_some_and(|table| table.contains(&fact.params))
+    }
+
+    // The number of facts in every relation that has at least one
+    pub fn sizes(&self) -> BTreeMap<String, usize> {
+        self.tables
+            .iter()
+            .map(|(name, table)| (name.clone(), table.len()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.values().map(|table| table.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    // Relations in order of their names, facts in the order of the relation (see sort)
+    pub fn facts(&self) -> impl Iterator<Item = FactRef<'_>> {
+        self.relations()
+            .into_iter()
+            .flat_map(|name| self.tables[name].facts())
+    }
+
+    pub fn into_facts(self) -> Vec<Fact> {
+        self.facts().map(|fact| fact.to_fact()).collect()
+    }
+
+    // Sorts the facts of every relation, so that facts() is the same on every run.
+    // Facts are compared column by column, numerically if every value of the column is an
+    // integer and as strings otherwise.
+    pub fn sort(&mut self) {
+        for table in self.tables.values_mut() {
+            if let Some(eqrel) = &mut table.eqrel {
+                eqrel.sort();
+                continue;
+            }
+            table.compact();
+            let rows = table.rows(0..table.len).collect::<Vec<_>>();
+            let numeric = (0..table.arity)
+                .map(|column| {
+                    rows.iter()
+                        .all(|row| row.get(column).parse::<i64>().is_ok())
+                })
+                .collect::<Vec<_>>();
+            let order = numeric.contains(&true).then(|| {
+                let mut order = (0..rows.len()).collect::<Vec<_>>();
+                order.sort_by(|a, b| compare_params(rows[*a], rows[*b], &numeric));
+                order
+            });
+            table.order = order;
+        }
+    }
+
+    // Drops every relation that is not in `names`
+    pub(crate) fn retain_relations(&mut self, names: &HashSet<&str>) {
+        self.tables.retain(|name, _| names.contains(name.as_str()));
+    }
+}
+
+fn compare_params(a: Row, b: Row, numeric: &[bool]) -> Ordering {
+    for (column, numeric) in numeric.iter().enumerate() {
+        let (a, b) = (a.get(column), b.get(column));
+        let ordering = if *numeric {
+            a.parse::<i64>().unwrap().cmp(&b.parse::<i64>().unwrap())
+        } else {
+            a.cmp(b)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+// Adds the facts to their tables, returning the facts that were not in the database yet,
+// by relation name
+pub(crate) fn extend_database(database: &mut Database, facts: &[Fact]) -> Vec<Fact> {
+    let mut batches: BTreeMap<&str, Vec<Vec<String>>> = BTreeMap::new();
+    for fact in facts {
+        batches
+            .entry(&fact.name)
+            .or_default()
+            .push(fact.params.clone());
+    }
+    let mut new_facts = vec![];
+    for (name, tuples) in batches {
+        let arity = tuples[0].len();
+        let eqrel = database.eqrels.contains(name);
+        let lattice = database.lattices.get(name);
+        let table = database.tables.entry(name.to_string()).or_insert_with(|| {
+            if eqrel {
+                Table::new_eqrel(name)
+            } else if let Some(lattice) = lattice {
+                Table::new_lattice(name, arity, lattice)
+            } else {
+                Table::new(name, arity)
+            }
+        });
+        new_facts.extend(table.insert(tuples).into_iter().map(|params| Fact {
+            name: name.to_string(),
+            params,
+        }));
+    }
+    new_facts
+}
+
+pub(crate) fn remove_from_database(database: &mut Database, facts: &HashSet<Fact>) {
+    for table in database.tables.values_mut() {
+        let name = table.name.clone();
+        let mut tuples = table.tuples();
+        tuples.retain(|params| {
+            !facts.contains(&Fact {
+                name: name.clone(),
+                params: params.clone(),
+            })
+        });
+        if tuples.len() < table.len {
+            let mut rebuilt = Table::new(&name, table.arity);
+            rebuilt.insert(tuples);
+            *table = rebuilt;
+        }
+    }
+    database.tables.retain(|_, table| table.len() > 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extend_database, Database, Table};
+    use crate::implem::run_datalog;
+    use crate::parse::{parse_fact_vector, parse_program, Fact, Token};
+    use logos::Logos;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_query_pattern() {
+        let facts = parse_fact_vector(&mut Token::lexer(
+            "4 var_live(b_instr_0, x); var_live(b_instr_0, y); var_live(b_instr_1, x); edge(a, b);",
+        ))
+        .unwrap();
+        let database = Database::from_facts(facts);
+
+        let mut live = database
+            .query("var_live", [Some("b_instr_0"), None])
+            .map(|fact| fact.param(1))
+            .collect::<Vec<_>>();
+        live.sort();
+        assert_eq!(live, vec!["x", "y"]);
+        let fact = database
+            .query("var_live", [None, Some("y")])
+            .next()
+            .unwrap();
+        assert_eq!(fact.to_string(), "var_live(b_instr_0, y)");
+        assert_eq!(fact.to_fact().params, vec!["b_instr_0", "y"]);
+        assert_eq!(database.query("var_live", [None, Some("x")]).count(), 2);
+        assert_eq!(database.query("var_live", [None]).count(), 0);
+        assert_eq!(database.sizes()["var_live"], 3);
+        assert_eq!(database.relations(), vec!["edge", "var_live"]);
+    }
+
+    #[test]
+    fn test_table_runs() {
+        let tuple = |x: u32, y: u32| vec![x.to_string(), y.to_string()];
+        let mut table = Table::new("edge", 2);
+        // duplicates in a batch and facts already in the table are not new
+        assert_eq!(
+            table.insert(vec![tuple(1, 2), tuple(1, 2)]),
+            vec![tuple(1, 2)]
+        );
+        assert_eq!(
+            table.insert(vec![tuple(1, 2), tuple(0, 5)]),
+            vec![tuple(0, 5)]
+        );
+        assert_eq!(table.runs.len(), 1);
+        for x in 2..40 {
+            table.insert(vec![tuple(x, x + 1)]);
+        }
+        assert_eq!(table.len(), 40);
+        assert!(table.runs.len() <= 6);
+        assert!(table.contains(&tuple(17, 18)));
+        assert!(!table.contains(&tuple(17, 17)));
+        // a prefix of a fact is not a fact
+        assert!(!table.contains(&["17".to_string()]));
+        let prefix = table
+            .prefix(&["1"])
+            .map(|row| row.params())
+            .collect::<Vec<_>>();
+        assert_eq!(prefix, vec![tuple(1, 2)]);
+        assert_eq!(table.rows(0..table.len()).count(), 40);
+        table.compact();
+        assert_eq!(table.runs.len(), 1);
+        assert_eq!(table.rows(10..12).count(), 2);
+    }
+
+    #[test]
+    fn test_sort_numeric_columns() {
+        let facts = parse_fact_vector(&mut Token::lexer(
+            "4 succ(b, a); succ(a, b); edge(b, a); edge(a, c);",
+        ))
+        .unwrap();
+        let mut database = Database::from_facts(facts);
+        extend_database(
+            &mut database,
+            &[
+                Fact {
+                    name: "succ".to_string(),
+                    params: vec!["a".to_string(), "10".to_string()],
+                },
+                Fact {
+                    name: "succ".to_string(),
+                    params: vec!["a".to_string(), "9".to_string()],
+                },
+            ],
+        );
+        database.sort();
+        let order = database
+            .facts()
+            .map(|fact| fact.to_string())
+            .collect::<Vec<_>>();
+        // the second column of succ has a non-numeric value, so it is compared as strings
+        assert_eq!(
+            order,
+            vec![
+                "edge(a, c)",
+                "edge(b, a)",
+                "succ(a, 10)",
+                "succ(a, 9)",
+                "succ(a, b)",
+                "succ(b, a)"
+            ]
+        );
+
+        let mut numbers = Database::new();
+        extend_database(
+            &mut numbers,
+            &["10", "9", "-1"]
+                .iter()
+                .map(|n| Fact {
+                    name: "n".to_string(),
+                    params: vec![n.to_string()],
+                })
+                .collect::<Vec<_>>(),
+        );
+        numbers.sort();
+        let order = numbers
+            .facts()
+            .map(|fact| fact.param(0))
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec!["-1", "9", "10"]);
+    }
+
+    #[test]
+    fn test_eqrel() {
+        let pair = |x: &str, y: &str| vec![x.to_string(), y.to_string()];
+        let mut table = Table::new_eqrel("alias");
+        // the new elements with themselves, and b with the root of the class it moved to
+        assert_eq!(
+            table.insert(vec![pair("a", "b")]),
+            vec![pair("a", "a"), pair("b", "a"), pair("b", "b")]
+        );
+        // already implied
+        assert!(table.insert(vec![pair("b", "a")]).is_empty());
+        assert_eq!(
+            table.insert(vec![pair("b", "c"), pair("d", "e")]).len(),
+            3 + 2
+        );
+        assert_eq!(table.len(), 9 + 4);
+        assert!(table.contains(&pair("c", "a")));
+        assert!(!table.contains(&pair("a", "d")));
+        let prefix = table
+            .prefix(&["c"])
+            .map(|row| row.params())
+            .collect::<Vec<_>>();
+        assert_eq!(prefix, vec![pair("c", "a"), pair("c", "b"), pair("c", "c")]);
+
+        // adding an element to a class of n elements adds 2n + 1 pairs, but only the element
+        // goes into the delta, and nothing is stored per pair
+        let mut database = Database::new();
+        database.eqrels.insert("alias".to_string());
+        let n = 100;
+        let chain = (1..n)
+            .map(|i| Fact {
+                name: "alias".to_string(),
+                params: vec!["0".to_string(), i.to_string()],
+            })
+            .collect::<Vec<_>>();
+        extend_database(&mut database, &chain);
+        let added = Fact {
+            name: "alias".to_string(),
+            params: vec!["0".to_string(), "x".to_string()],
+        };
+        let delta = extend_database(&mut database, &[added]);
+        assert_eq!(delta.len(), 2);
+        let eqrel = database.tables["alias"].eqrel.as_ref().unwrap();
+        assert_eq!(eqrel.members.iter().map(Vec::len).sum::<usize>(), n + 1);
+        assert_eq!(database.len(), (n + 1) * (n + 1));
+        let delta = Database::from_facts(delta);
+        let delta_table = &delta.tables["alias"];
+        let pairs = database
+            .delta_rows(delta_table, 0..delta_table.len())
+            .map(|row| row.params())
+            .collect::<HashSet<_>>();
+        assert_eq!(pairs.len(), 2 * n + 1);
+
+        // the same relation as with rules for reflexivity, symmetry and transitivity
+        let rules = r#"
+            .decl assign(x, y) .input;
+            .decl points_to(x, o) .input;
+            .decl alias(x, y) .output;
+            .decl may_point(x, o) .output;
+            .rule alias(x, y) :- 1 assign(x, y);
+            .rule alias(x, y) :- 2 alias(x, z), assign(z, y);
+            .rule may_point(x, o) :- 2 alias(x, y), points_to(y, o);
+        "#;
+        let closure = r#"
+            .rule alias(x, x) :- 1 alias(x, y);
+            .rule alias(y, x) :- 1 alias(x, y);
+            .rule alias(x, z) :- 2 alias(x, y), alias(y, z);
+        "#;
+        let facts = parse_fact_vector(&mut Token::lexer(
+            "5 assign(p, q); assign(r, q); assign(s, t); points_to(r, o1); points_to(t, o2);",
+        ))
+        .unwrap();
+        let run = |src: &str| {
+            let program = parse_program(&mut Token::lexer(src)).unwrap();
+            let mut facts = run_datalog(&program, facts.clone())
+                .unwrap()
+                .facts()
+                .map(|fact| fact.to_string())
+                .collect::<Vec<_>>();
+            facts.sort();
+            facts
+        };
+        let eqrel = run(&rules.replace("alias(x, y) .output", "alias(x, y) .eqrel .output"));
+        assert_eq!(eqrel, run(&format!("{}{}", rules, closure)));
+        assert_eq!(eqrel.len(), 3 * 3 + 2 * 2 + 3 + 2);
+    }
+}
@@ -0,0 +1,578 @@
+use crate::constraint::{check_constraints, Violation};
+use crate::database::{extend_database, remove_from_database, Database};
+use crate::external::{ExternalRelation, Externals};
+use crate::implem::{apply_rule, run_semi_naive, verify_facts, Limits, Recorder};
+use crate::lattice::Lattice;
+use crate::parse::{parse_fact_vector, parse_program, DeclKind, Fact, Program, Token};
+use crate::plan::{plan_program_with_externals, Plan};
+use crate::tuple::Tuple;
+use logos::Logos;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+
+// A persistent evaluation of a program. Unlike run_datalog, the evaluated database is kept
+// around so that new input facts only have their consequences derived instead of
+// re-running the whole program from scratch.
+pub struct Engine {
+    program: Program,
+    plan: Plan,
+    // the facts that were inserted directly, as opposed to derived by a rule
+    inputs: HashSet<Fact>,
+    database: Database,
+    // facts inserted through a Relation handle that the next run() propagates
+    pending: Vec<Fact>,
+    // whether the rules have been run, the tuples of external relations are only new to
+    // the first run
+    evaluated: bool,
+}
+
+// Loads a program for an Engine, either parsed already or from datalog source, along with
+// any initial facts. Errors are reported by build().
+#[derive(Default)]
+pub struct EngineBuilder {
+    program: Option<Result<Program, String>>,
+    facts: Vec<Fact>,
+    facts_error: Option<String>,
+    externals: Externals,
+}
+
+impl EngineBuilder {
+    pub fn new() -> EngineBuilder {
+        EngineBuilder::default()
+    }
+
+    pub fn program(mut self, program: Program) -> EngineBuilder {
+        self.program = Some(Ok(program));
+        self
+    }
+
+    // the program as datalog source, `.decl` and `.rule` statements
+    pub fn source(mut self, src: &str) -> EngineBuilder {
+        self.program = Some(
+            parse_program(&mut Token::lexer(src))
+                .map_err(|e| format!("Error parsing rules: {}", e)),
+        );
+        self
+    }
+
+    pub fn facts(mut self, facts: Vec<Fact>) -> EngineBuilder {
+        self.facts.extend(facts);
+        self
+    }
+
+    // facts in the .in format, the number of facts followed by the facts
+    pub fn facts_source(mut self, src: &str) -> EngineBuilder {
+        match parse_fact_vector(&mut Token::lexer(src)) {
+            Ok(facts) => self.facts.extend(facts),
+            Err(e) => self.facts_error = Some(format!("Error parsing facts: {}", e)),
+        }
+        self
+    }
+
+    // An external predicate the rules can call as `@name(...)`, see Externals::predicate
+    pub fn predicate(
+        mut self,
+        name: &str,
+        arity: usize,
+        predicate: impl Fn(&[&str]) -> bool + Send + Sync + 'static,
+    ) -> EngineBuilder {
+        self.externals.predicate(name, arity, predicate);
+        self
+    }
+
+    // An external functor the rules can call as `@name(..., result)`, see Externals::functor
+    pub fn functor(
+        mut self,
+        name: &str,
+        arity: usize,
+        functor: impl Fn(&[&str]) -> String + Send + Sync + 'static,
+    ) -> EngineBuilder {
+        self.externals.functor(name, arity, functor);
+        self
+    }
+
+    // Backs the declared relation `name` with tuples from Rust, see Externals::relation
+    pub fn external_relation(
+        mut self,
+        name: &str,
+        relation: impl ExternalRelation + Send + Sync + 'static,
+    ) -> EngineBuilder {
+        self.externals.relation(name, relation);
+        self
+    }
+
+    // The value type of `lattice<name>` declarations, see Externals::lattice
+    pub fn lattice<L: Lattice>(mut self, name: &str) -> EngineBuilder {
+        self.externals.lattice::<L>(name);
+        self
+    }
+
+    // Plans the program and evaluates it on the initial facts
+    pub fn build(self) -> Result<Engine, String> {
+        let program = self
+            .program
+            .unwrap_or_else(|| Err("No program given to the engine".to_string()))?;
+        if let Some(e) = self.facts_error {
+            return Err(e);
+        }
+        let mut engine = Engine::with_externals(program, &self.externals)?;
+        engine.insert_facts(self.facts)?;
+        Ok(engine)
+    }
+}
+
+// A typed view of one relation of an Engine. The schema is checked against the declaration
+// of the relation on every access, so a mismatch is an error rather than a panic.
+pub struct Relation<'a, T> {
+    engine: &'a mut Engine,
+    name: String,
+    tuple: PhantomData<T>,
+}
+
+impl<T: Tuple> Relation<'_, T> {
+    fn check_schema(&self) -> Result<(), String> {
+        let Some(decl) = self
+            .engine
+            .program
+            .decls
+            .iter()
+            .find(|decl| decl.name == self.name)
+        else {
+            return Err(format!("Relation {} not declared", self.name));
+        };
+        if decl.params.len() != T::ARITY {
+            return Err(format!(
+                "Relation {} has {} parameters, but the tuple type has {}",
+                self.name,
+                decl.params.len(),
+                T::ARITY
+            ));
+        }
+        Ok(())
+    }
+
+    // Queues a tuple for the next Engine::run. Only input relations can be inserted into.
+    pub fn insert(&mut self, tuple: T) -> Result<(), String> {
+        self.extend([tuple])
+    }
+
+    pub fn extend(&mut self, tuples: impl IntoIterator<Item = T>) -> Result<(), String> {
+        self.check_schema()?;
+        let facts = tuples
+            .into_iter()
+            .map(|tuple| Fact {
+                name: self.name.clone(),
+                params: tuple.to_params(),
+            })
+            .collect::<Vec<_>>();
+        verify_facts(&self.engine.program, &facts)?;
+        self.engine.pending.extend(facts);
+        Ok(())
+    }
+
+    // The tuples currently in the relation, without the ones still waiting for run()
+    pub fn iter(&self) -> Result<impl Iterator<Item = T>, String> {
+        self.check_schema()?;
+        let tuples = self
+            .engine
+            .database
+            .relation(&self.name)
+            .map(|fact| {
+                T::from_params(&fact.to_fact().params)
+                    .map_err(|e| format!("Error reading relation {}: {}", self.name, e))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(tuples.into_iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.engine
+            .database
+            .tables
+            .get(&self.name)
+            .map_or(0, |table| table.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, tuple: &T) -> bool {
+        self.engine.contains(&Fact {
+            name: self.name.clone(),
+            params: tuple.to_params(),
+        })
+    }
+}
+
+impl Engine {
+    pub fn new(program: Program) -> Result<Engine, String> {
+        Engine::with_externals(program, &Externals::default())
+    }
+
+    pub fn with_externals(program: Program, externals: &Externals) -> Result<Engine, String> {
+        let plan = plan_program_with_externals(&program, externals)?;
+        let database = Database::for_program(&program, externals);
+        Ok(Engine {
+            program,
+            plan,
+            inputs: HashSet::new(),
+            database,
+            pending: vec![],
+            evaluated: false,
+        })
+    }
+
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::new()
+    }
+
+    // A typed handle on relation `name`, e.g. `engine.relation::<(String, String)>("edge")`
+    pub fn relation<T: Tuple>(&mut self, name: &str) -> Relation<'_, T> {
+        Relation {
+            engine: self,
+            name: name.to_string(),
+            tuple: PhantomData,
+        }
+    }
+
+    // Propagates the tuples inserted through relation handles since the last run.
+    // Returns every fact that is new to the database, inserted or derived. The new pairs of
+    // an .eqrel relation are given by the pair of every element that changed class and the
+    // root of its class.
+    pub fn run(&mut self) -> Result<Vec<Fact>, String> {
+        let pending = std::mem::take(&mut self.pending);
+        self.insert_facts(pending)
+    }
+
+    // The names of the .output relations of the program
+    pub fn output_relations(&self) -> Vec<String> {
+        self.program
+            .decls
+            .iter()
+            .filter(|decl| decl.kind == DeclKind::Output)
+            .map(|decl| decl.name.clone())
+            .collect()
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    // The constraints and assertions of the program that do not hold on the current
+    // database. They are not checked on insertion or retraction, call this after a batch.
+    pub fn violations(&self) -> Vec<Violation> {
+        check_constraints(&self.program, &self.plan, &self.database)
+    }
+
+    // Adds input facts and propagates them through the rules.
+    // Returns every fact that is new to the database, inserted or derived. The new pairs of
+    // an .eqrel relation are given by the pair of every element that changed class and the
+    // root of its class.
+    pub fn insert_facts(&mut self, facts: Vec<Fact>) -> Result<Vec<Fact>, String> {
+        verify_facts(&self.program, &facts)?;
+
+        self.database.check_lattice_values(&facts)?;
+
+        self.inputs.extend(facts.iter().cloned());
+        let delta = extend_database(&mut self.database, &facts);
+        let derived = self.propagate(delta.clone())?;
+
+        Ok(delta.into_iter().chain(derived).collect())
+    }
+
+    // Runs the rules on the facts just added to the database, see run_semi_naive
+    fn propagate(&mut self, delta: Vec<Fact>) -> Result<Vec<Fact>, String> {
+        let first_run = !self.evaluated;
+        self.evaluated = true;
+        run_semi_naive(
+            &self.program,
+            &self.plan,
+            &mut self.database,
+            delta,
+            first_run,
+            &mut Recorder::default(),
+            &Limits::default(),
+        )
+        .map_err(|stopped| stopped.to_string())
+    }
+
+    // Removes input facts using Delete-and-Rederive:
+    // 1. over-delete every fact with a derivation that uses a retracted fact
+    // 2. rederive the over-deleted facts that still have a derivation from what is left
+    // 3. propagate the rederived facts like an insertion
+    // Returns the facts that are no longer in the database.
+    pub fn retract_facts(&mut self, facts: Vec<Fact>) -> Result<Vec<Fact>, String> {
+        verify_facts(&self.program, &facts)?;
+        // a union-find can't split a class again
+        if let Some(decl) = self.program.decls.iter().find(|decl| decl.eqrel) {
+            return Err(format!(
+                "Cannot retract facts from a program with equivalence relation {}",
+                decl.name
+            ));
+        }
+        // nor can a joined value be taken apart
+        if let Some(decl) = self
+            .program
+            .decls
+            .iter()
+            .find(|decl| decl.lattice.is_some())
+        {
+            return Err(format!(
+                "Cannot retract facts from a program with lattice-valued relation {}",
+                decl.name
+            ));
+        }
+
+        let retracted = facts
+            .into_iter()
+            .filter(|fact| self.inputs.remove(fact))
+            .collect::<Vec<_>>();
+
+        let mut deleted: HashSet<Fact> = retracted.iter().cloned().collect();
+        let mut frontier = Database::new();
+        extend_database(&mut frontier, &retracted);
+        loop {
+            let mut new_deleted = vec![];
+            for rule_plan in self.plan.rule_plans() {
+                for fact in apply_rule(
+                    &self.program,
+                    &self.plan,
+                    rule_plan,
+                    &self.database,
+                    &frontier,
+                ) {
+                    if self.database.contains(&fact) && deleted.insert(fact.clone()) {
+                        new_deleted.push(fact);
+                    }
+                }
+            }
+            if new_deleted.is_empty() {
+                break;
+            }
+            frontier = Database::new();
+            extend_database(&mut frontier, &new_deleted);
+        }
+
+        remove_from_database(&mut self.database, &deleted);
+
+        let deleted_names = deleted
+            .iter()
+            .map(|fact| fact.name.clone())
+            .collect::<HashSet<_>>();
+        let mut rederived = deleted
+            .iter()
+            .filter(|fact| self.inputs.contains(*fact))
+            .cloned()
+            .collect::<HashSet<_>>();
+        for rule_plan in self.plan.rule_plans() {
+            if !deleted_names.contains(&self.program.rules[rule_plan.rule].head.name) {
+                continue;
+            }
+            for fact in apply_rule(
+                &self.program,
+                &self.plan,
+                rule_plan,
+                &self.database,
+                &self.database,
+            ) {
+                if deleted.contains(&fact) {
+                    rederived.insert(fact);
+                }
+            }
+        }
+
+        let rederived = rederived.into_iter().collect::<Vec<_>>();
+        extend_database(&mut self.database, &rederived);
+        self.propagate(rederived)?;
+
+        Ok(deleted
+            .into_iter()
+            .filter(|fact| !self.database.contains(fact))
+            .collect())
+    }
+
+    pub fn contains(&self, fact: &Fact) -> bool {
+        self.database.contains(fact)
+    }
+
+    pub fn facts(&self) -> Vec<Fact> {
+        self.database.facts().map(|fact| fact.to_fact()).collect()
+    }
+
+    // Every relation, inputs included
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    // The evaluation plan of the program, printable like `datalogint run --plan`
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Engine;
+    use crate::implem::{run_datalog_with_options, RunOptions};
+    use crate::lattice::ConstLattice;
+    use crate::parse::{parse_fact_vector, parse_program, Fact, Token};
+    use logos::Logos;
+    use std::collections::HashSet;
+
+    const REACHABLE_SRC: &str = r#"
+    .decl edge(x, y) .input;
+    .decl reachable(x, y) .output;
+    .rule reachable(x, y) :- 1 edge(x, y);
+    .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+    "#;
+
+    fn facts(src: &str) -> Vec<Fact> {
+        parse_fact_vector(&mut Token::lexer(src)).unwrap()
+    }
+
+    // every fact of a run from scratch, inputs included
+    fn full_run(src: &str) -> HashSet<Fact> {
+        let program = parse_program(&mut Token::lexer(REACHABLE_SRC)).unwrap();
+        let options = RunOptions {
+            all_relations: true,
+            ..RunOptions::default()
+        };
+        run_datalog_with_options(&program, facts(src), &options)
+            .unwrap()
+            .database
+            .into_facts()
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_insert_matches_full_run() {
+        let program = parse_program(&mut Token::lexer(REACHABLE_SRC)).unwrap();
+        let mut engine = Engine::new(program).unwrap();
+
+        engine
+            .insert_facts(facts("2 edge(a, b); edge(c, d);"))
+            .unwrap();
+        let new_facts = engine.insert_facts(facts("1 edge(b, c);")).unwrap();
+        assert!(new_facts.contains(&Fact {
+            name: "reachable".to_string(),
+            params: vec!["a".to_string(), "d".to_string()],
+        }));
+
+        let expected = full_run("3 edge(a, b); edge(c, d); edge(b, c);");
+        assert_eq!(engine.facts().into_iter().collect::<HashSet<_>>(), expected);
+
+        // inserting a fact twice derives nothing new
+        assert!(engine
+            .insert_facts(facts("1 edge(b, c);"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_retract_matches_full_run() {
+        let program = parse_program(&mut Token::lexer(REACHABLE_SRC)).unwrap();
+        let mut engine = Engine::new(program).unwrap();
+        engine
+            .insert_facts(facts(
+                "5 edge(a, b); edge(b, c); edge(a, c); edge(c, d); edge(d, a);",
+            ))
+            .unwrap();
+
+        let removed = engine.retract_facts(facts("1 edge(d, a);")).unwrap();
+        assert!(removed.contains(&Fact {
+            name: "reachable".to_string(),
+            params: vec!["d".to_string(), "b".to_string()],
+        }));
+        // reachable(a, c) is still supported by edge(a, c) and by a -> b -> c
+        assert!(engine.contains(&Fact {
+            name: "reachable".to_string(),
+            params: vec!["a".to_string(), "c".to_string()],
+        }));
+
+        engine.retract_facts(facts("1 edge(b, c);")).unwrap();
+
+        let expected = full_run("3 edge(a, b); edge(a, c); edge(c, d);");
+        assert_eq!(engine.facts().into_iter().collect::<HashSet<_>>(), expected);
+    }
+
+    #[test]
+    fn test_typed_relations() {
+        let mut engine = Engine::builder().source(REACHABLE_SRC).build().unwrap();
+        engine
+            .relation::<(String, String)>("edge")
+            .extend([
+                ("a".to_string(), "b".to_string()),
+                ("b".to_string(), "c".to_string()),
+            ])
+            .unwrap();
+        // nothing is derived before run()
+        assert!(engine.relation::<(String, String)>("reachable").is_empty());
+        engine.run().unwrap();
+
+        let mut reachable = engine
+            .relation::<(String, String)>("reachable")
+            .iter()
+            .unwrap()
+            .collect::<Vec<_>>();
+        reachable.sort();
+        assert_eq!(
+            reachable,
+            vec![
+                ("a".to_string(), "b".to_string()),
+                ("a".to_string(), "c".to_string()),
+                ("b".to_string(), "c".to_string()),
+            ]
+        );
+
+        assert!(engine.relation::<(String,)>("edge").iter().is_err());
+        assert!(engine.relation::<(u32, u32)>("reachable").iter().is_err());
+        assert!(engine
+            .relation::<(String, String)>("reachable")
+            .insert(("c".to_string(), "a".to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_lattice_relation() {
+        let mut engine = Engine::builder()
+            .source(
+                r#"
+                .decl assign(v, c) .input;
+                .decl val(v, c: lattice<ConstLattice>) .output;
+                .rule val(v, c) :- 1 assign(v, c);
+                "#,
+            )
+            .lattice::<ConstLattice>("ConstLattice")
+            .build()
+            .unwrap();
+        let pair = |v: &str, c: &str| (v.to_string(), c.to_string());
+        engine
+            .relation::<(String, String)>("assign")
+            .insert(pair("x", "one"))
+            .unwrap();
+        engine.run().unwrap();
+        let val = |engine: &mut Engine| {
+            engine
+                .relation::<(String, String)>("val")
+                .iter()
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(val(&mut engine), vec![pair("x", "one")]);
+
+        // a second constant joins to top, which replaces the first one
+        engine
+            .relation::<(String, String)>("assign")
+            .insert(pair("x", "two"))
+            .unwrap();
+        let new_facts = engine.run().unwrap();
+        assert!(new_facts
+            .iter()
+            .any(|fact| fact.to_string() == "val(x, top)"));
+        assert_eq!(val(&mut engine), vec![pair("x", "top")]);
+        let facts = parse_fact_vector(&mut Token::lexer("1 assign(x, one);")).unwrap();
+        assert!(engine.retract_facts(facts).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::lattice::{ConstLattice, Lattice, LatticeOps};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// External predicates and functors: Rust closures that rule bodies call as `@name(...)`.
+// A predicate with n arguments is written `@name(a1, ..., an)` and holds when the closure
+// returns true. A functor with n arguments is written with one more parameter,
+// `@name(a1, ..., an, result)`, and binds `result` to what the closure returns (or checks it,
+// when `result` is already bound). The arguments have to be bound by the declarations
+// before the call, which the plan takes care of.
+//
+// External relations are declared relations whose tuples come from Rust data structures
+// instead of input facts, so large inputs don't have to be copied into facts first.
+//
+// Lattices are the value types of lattice-valued relations, see the lattice module.
+
+type Function = Arc<dyn Fn(&[&str]) -> Option<String> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExternalKind {
+    Predicate,
+    Functor,
+}
+
+#[derive(Clone)]
+pub(crate) struct External {
+    pub(crate) kind: ExternalKind,
+    // the number of arguments, without the result of a functor
+    pub(crate) arity: usize,
+    function: Function,
+}
+
+impl External {
+    // The number of parameters of the call in a rule body
+    pub(crate) fn num_params(&self) -> usize {
+        match self.kind {
+            ExternalKind::Predicate => self.arity,
+            ExternalKind::Functor => self.arity + 1,
+        }
+    }
+
+    // For a predicate Some(String::new()) if it holds, for a functor the result
+    pub(crate) fn call(&self, args: &[&str]) -> Option<String> {
+        (self.function)(args)
+    }
+}
+
+// The tuples of a relation that live outside of the database. The engine scans the
+// relation, or probes it with the columns that are already bound when the relation is
+// joined on them. Tuples have to have `arity` parameters and should not repeat.
+pub trait ExternalRelation {
+    fn arity(&self) -> usize;
+
+    fn scan(&self) -> Box<dyn Iterator<Item = Vec<String>> + '_>;
+
+    // The tuples where every Some column is equal to the given value. Defaults to filtering
+    // scan, implement it to look tuples up in an index instead.
+    fn probe<'a>(
+        &'a self,
+        bound: &'a [Option<&'a str>],
+    ) -> Box<dyn Iterator<Item = Vec<String>> + 'a> {
+        Box::new(self.scan().filter(move |tuple| {
+            tuple
+                .iter()
+                .zip(bound)
+                .all(|(param, value)| value.is_none_or(|value| value == param))
+        }))
+    }
+}
+
+// An external relation that calls a closure for its tuples on every scan
+struct IterRelation<F> {
+    arity: usize,
+    tuples: F,
+}
+
+impl<F, I> ExternalRelation for IterRelation<F>
+where
+    F: Fn() -> I,
+    I: IntoIterator<Item = Vec<String>>,
+    I::IntoIter: 'static,
+{
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn scan(&self) -> Box<dyn Iterator<Item = Vec<String>> + '_> {
+        Box::new((self.tuples)().into_iter())
+    }
+}
+
+// The external predicates and functors available to a program, by name including the `@`,
+// the external relations, by the name of their declaration, and the lattices, by the name
+// used in `lattice<...>`
+#[derive(Clone, Default)]
+pub struct Externals {
+    functions: HashMap<String, External>,
+    relations: HashMap<String, Arc<dyn ExternalRelation + Send + Sync>>,
+    lattices: HashMap<String, LatticeOps>,
+}
+
+pub(crate) fn is_external(name: &str) -> bool {
+    name.starts_with('@')
+}
+
+fn external_name(name: &str) -> String {
+    if is_external(name) {
+        name.to_string()
+    } else {
+        format!("@{}", name)
+    }
+}
+
+// The externals that the command line and the web demo register for every program: the
+// ConstLattice lattice
+pub fn builtin_externals() -> Externals {
+    let mut externals = Externals::new();
+    externals.lattice::<ConstLattice>("ConstLattice");
+    externals
+}
+
+impl Externals {
+    pub fn new() -> Externals {
+        Externals::default()
+    }
+
+    // Registers `@name` with `arity` arguments, e.g. `@is_prefix(x, y)`
+    pub fn predicate(
+        &mut self,
+        name: &str,
+        arity: usize,
+        predicate: impl Fn(&[&str]) -> bool + Send + Sync + 'static,
+    ) {
+        self.functions.insert(
+            external_name(name),
+            External {
+                kind: ExternalKind::Predicate,
+                arity,
+                function: Arc::new(move |args| predicate(args).then(String::new)),
+            },
+        );
+    }
+
+    // Registers `@name` with `arity` arguments and a result, e.g. `@hash(x, h)` for arity 1
+    pub fn functor(
+        &mut self,
+        name: &str,
+        arity: usize,
+        functor: impl Fn(&[&str]) -> String + Send + Sync + 'static,
+    ) {
+        self.functions.insert(
+            external_name(name),
+            External {
+                kind: ExternalKind::Functor,
+                arity,
+                function: Arc::new(move |args| Some(functor(args))),
+            },
+        );
+    }
+
+    // Backs the declared relation `name` with `relation`. Input facts of the relation are
+    // still read as well.
+    pub fn relation(
+        &mut self,
+        name: &str,
+        relation: impl ExternalRelation + Send + Sync + 'static,
+    ) {
+        self.relations.insert(name.to_string(), Arc::new(relation));
+    }
+
+    // Backs the declared relation `name` with the tuples `tuples` returns, e.g.
+    // `move || blocks.clone().into_iter().map(|block| vec![block.name])`
+    pub fn relation_iter<I>(
+        &mut self,
+        name: &str,
+        arity: usize,
+        tuples: impl Fn() -> I + Send + Sync + 'static,
+    ) where
+        I: IntoIterator<Item = Vec<String>>,
+        I::IntoIter: 'static,
+    {
+        self.relation(name, IterRelation { arity, tuples });
+    }
+
+    // Registers the Lattice `L` for declarations like `.decl val(x, v: lattice<name>)`
+    pub fn lattice<L: Lattice>(&mut self, name: &str) {
+        self.lattices
+            .insert(name.to_string(), LatticeOps::new::<L>(name));
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&External> {
+        self.functions.get(name)
+    }
+
+    pub(crate) fn get_relation(&self, name: &str) -> Option<&(dyn ExternalRelation + Send + Sync)> {
+        self.relations.get(name).map(|relation| relation.as_ref())
+    }
+
+    pub(crate) fn get_lattice(&self, name: &str) -> Option<&LatticeOps> {
+        self.lattices.get(name)
+    }
+}
+
+impl fmt::Debug for Externals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = self
+            .functions
+            .keys()
+            .chain(self.relations.keys())
+            .chain(self.lattices.keys())
+            .collect::<Vec<_>>();
+        names.sort();
+        f.debug_tuple("Externals").field(&names).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExternalRelation, Externals};
+    use crate::implem::{run_datalog_with_options, RunOptions};
+    use crate::parse::{parse_fact_vector, parse_program, Fact, Token};
+    use crate::plan::plan_program_with_externals;
+    use logos::Logos;
+
+    #[test]
+    fn test_predicate_and_functor() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl word(x) .input;
+            .decl prefix(x, y) .output;
+            .decl len(x, n) .output;
+            .rule prefix(x, y) :- 3 word(x), word(y), @is_prefix(x, y);
+            .rule len(x, n) :- 2 @length(x, n), word(x);
+            "#,
+        ))
+        .unwrap();
+        let facts =
+            parse_fact_vector(&mut Token::lexer("3 word(ab); word(abc); word(b);")).unwrap();
+        let mut externals = Externals::new();
+        externals.predicate("is_prefix", 2, |args| {
+            args[0] != args[1] && args[1].starts_with(args[0])
+        });
+        externals.functor("length", 1, |args| args[0].len().to_string());
+
+        let options = RunOptions {
+            externals: externals.clone(),
+            sorted: true,
+            ..RunOptions::default()
+        };
+        let database = run_datalog_with_options(&program, facts, &options)
+            .unwrap()
+            .database;
+        let output = database
+            .facts()
+            .map(|fact| fact.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            output,
+            vec!["len(ab, 2)", "len(abc, 3)", "len(b, 1)", "prefix(ab, abc)"]
+        );
+
+        // the functor's argument has to be bound, and the arity has to match
+        let mut wrong_arity = Externals::new();
+        wrong_arity.predicate("is_prefix", 1, |_| true);
+        wrong_arity.functor("length", 1, |_| String::new());
+        assert!(plan_program_with_externals(&program, &wrong_arity).is_err());
+        let unbound = parse_program(&mut Token::lexer(
+            r#"
+            .decl word(x) .input;
+            .decl len(x, n) .output;
+            .rule len(x, n) :- 2 word(x), @length(y, n);
+            "#,
+        ))
+        .unwrap();
+        assert!(plan_program_with_externals(&unbound, &externals).is_err());
+    }
+
+    // succ(x, x + 1) for x below n, looked up directly when x is bound
+    struct Successors {
+        n: u32,
+    }
+
+    impl ExternalRelation for Successors {
+        fn arity(&self) -> usize {
+            2
+        }
+
+        fn scan(&self) -> Box<dyn Iterator<Item = Vec<String>> + '_> {
+            Box::new((0..self.n).map(|x| vec![x.to_string(), (x + 1).to_string()]))
+        }
+
+        fn probe<'a>(
+            &'a self,
+            bound: &'a [Option<&'a str>],
+        ) -> Box<dyn Iterator<Item = Vec<String>> + 'a> {
+            match bound[0].and_then(|x| x.parse::<u32>().ok()) {
+                Some(x) if x < self.n && bound[1].is_none() => {
+                    Box::new(std::iter::once(vec![x.to_string(), (x + 1).to_string()]))
+                }
+                _ => Box::new(self.scan().filter(move |tuple| {
+                    tuple
+                        .iter()
+                        .zip(bound)
+                        .all(|(param, value)| value.is_none_or(|value| value == param))
+                })),
+            }
+        }
+    }
+
+    #[test]
+    fn test_external_relation() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl start(x) .input;
+            .decl succ(x, y) .input;
+            .decl reach(x) .output;
+            .rule reach(x) :- 1 start(x);
+            .rule reach(y) :- 2 reach(x), succ(x, y);
+            "#,
+        ))
+        .unwrap();
+        let mut externals = Externals::new();
+        externals.relation("succ", Successors { n: 5 });
+        let start = Fact {
+            name: "start".to_string(),
+            params: vec!["2".to_string()],
+        };
+        let options = RunOptions {
+            externals: externals.clone(),
+            all_relations: true,
+            sorted: true,
+            ..RunOptions::default()
+        };
+        let database = run_datalog_with_options(&program, vec![start], &options)
+            .unwrap()
+            .database;
+        let reach = database
+            .relation("reach")
+            .map(|fact| fact.param(0))
+            .collect::<Vec<_>>();
+        assert_eq!(reach, vec!["2", "3", "4", "5"]);
+        // the tuples of succ are not copied into the database
+        assert!(database.relation("succ").next().is_none());
+        let plan = plan_program_with_externals(&program, &externals).unwrap();
+        assert!(plan
+            .to_string()
+            .contains("probe external succ(x, y) on [x]"));
+
+        let mut wrong_arity = Externals::new();
+        wrong_arity.relation_iter("succ", 1, || vec![vec!["0".to_string()]]);
+        assert!(plan_program_with_externals(&program, &wrong_arity).is_err());
+    }
+}
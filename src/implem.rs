@@ -1,10 +1,23 @@
+use crate::constraint::{check_constraints, Violation};
+use crate::database::{extend_database, Database, Row, Table};
+use crate::external::{is_external, External, ExternalKind, ExternalRelation, Externals};
+use crate::leapfrog::leapfrog_variant;
+use crate::parallel::evaluate_rules_parallel;
 use crate::parse::{DeclKind, Declaration, Fact, FactLike, Program, Rule};
+use crate::plan::{plan_program_with_externals, JoinStep, JoinStrategy, Plan, RulePlan, Stratum};
+use crate::profile::{Profile, RuleProfile};
+use crate::progress::{CancellationToken, Progress};
+use crate::provenance::{Derivation, Provenance};
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use thiserror::Error;
 
 //  Verify that the facts are valid according to the program
 //  Facts have correct number of arguments
 //  Facts are made up of existing declarations
-fn verify_facts(program: &Program, facts: &Vec<Fact>) -> Result<(), String> {
+pub(crate) fn verify_facts(program: &Program, facts: &Vec<Fact>) -> Result<(), String> {
     let mut decl_map = HashMap::new();
 
     for decl in &program.decls {
@@ -30,30 +43,21 @@ fn verify_facts(program: &Program, facts: &Vec<Fact>) -> Result<(), String> {
     Ok(())
 }
 
-// a fact is characterized by a mapping from relation name to a set of parameters
-// A table is a list of facts with the same relation name
-// when we run datalog, we are "joining" the tables in the rules on a specified set of keys
-#[derive(Debug)]
-struct Table {
-    name: String,
-    facts: Vec<Fact>,
-}
-
 // a mapping of parameter names to parameter names, to be hash joined with a table
 #[derive(Clone, Debug)]
 pub struct ParameterMapping {
     // the parameter mappings that are being joined on
     // we use a BTreeMap to because it can be hashed
-    parameter_maps: HashSet<BTreeMap<String, String>>,
+    pub(crate) parameter_maps: HashSet<BTreeMap<String, String>>,
     // the parameter names that are being joined on
-    parameter_keys: HashSet<String>,
+    pub(crate) parameter_keys: HashSet<String>,
 }
 
 impl ParameterMapping {
     fn is_empty(&self) -> bool {
         self.parameter_keys.is_empty()
     }
-    fn new() -> ParameterMapping {
+    pub(crate) fn new() -> ParameterMapping {
         ParameterMapping {
             parameter_maps: HashSet::new(),
             parameter_keys: HashSet::new(),
@@ -68,15 +72,13 @@ fn join_parameter_mapping(a: &ParameterMapping, b: &ParameterMapping) -> Paramet
         return a.clone();
     }
 
+    // with no keys in common every mapping hashes to the same (empty) key,
+    // which makes this a cross product
     let intsct_keys = a
         .parameter_keys
         .intersection(&b.parameter_keys)
         .collect::<Vec<_>>();
 
-    if intsct_keys.is_empty() {
-        return ParameterMapping::new();
-    }
-
     let mut a_hashmap = HashMap::new();
     for pm in a.parameter_maps.iter() {
         let hash = intsct_keys
@@ -116,102 +118,854 @@ fn join_parameter_mapping(a: &ParameterMapping, b: &ParameterMapping) -> Paramet
     new_parameter_mapping
 }
 
-struct Database {
-    tables: HashMap<String, Table>,
+// A parameter repeated in a declaration, like x in edge(x, x), has to have the same value in
+// every column it appears in: the pairs of its first column and every later one
+fn repeated_columns(params: &[String]) -> Vec<(usize, usize)> {
+    params
+        .iter()
+        .enumerate()
+        .filter_map(|(column, p)| {
+            let first = params.iter().position(|other| other == p).unwrap();
+            (first != column).then_some((first, column))
+        })
+        .collect()
 }
 
-fn extend_database(database: &mut Database, facts: &Vec<Fact>) -> () {
-    for fact in facts {
-        database
-            .tables
-            .entry(fact.name.clone())
-            .or_insert(Table {
-                name: fact.name.clone(),
-                facts: vec![],
-            })
-            .facts
-            .push(fact.clone());
-    }
-}
-
-fn get_parameter_mapping(table: &Table, parameter_keys: &Vec<String>) -> ParameterMapping {
+fn get_parameter_mapping<'a>(
+    rows: impl Iterator<Item = Row<'a>>,
+    parameter_keys: &[String],
+) -> ParameterMapping {
     let mut parameter_mapping = ParameterMapping::new();
-    for fact in &table.facts {
+    let repeated = repeated_columns(parameter_keys);
+    for row in rows {
+        if repeated
+            .iter()
+            .any(|(first, column)| row.get(*first) != row.get(*column))
+        {
+            continue;
+        }
         parameter_mapping.parameter_maps.insert(
             parameter_keys
                 .iter()
-                .zip(fact.params().iter())
-                .map(|(k, v)| (k.clone(), v.clone()))
+                .enumerate()
+                .map(|(column, k)| (k.clone(), row.get(column).to_string()))
                 .collect(),
         );
     }
-    parameter_mapping.parameter_keys = parameter_keys.clone().into_iter().collect();
+    parameter_mapping.parameter_keys = parameter_keys.iter().cloned().collect();
     parameter_mapping
 }
 
-fn get_output_fact(rule: &Rule, parameter_mapping: &ParameterMapping) -> Vec<Fact> {
-    let mut facts = HashSet::new();
-    for pm in parameter_mapping.parameter_maps.iter() {
-        facts.insert(Fact {
-            name: rule.head.name.clone(),
-            params: rule
-                .head
+// Joins the parameter mappings with the rows of `table` that agree with them. The first
+// `prefix_len` parameters of the declaration are bound by every mapping, so the rows of a
+// mapping are found by binary search in each run of the table.
+fn probe_table(
+    table: &Table,
+    decl: &Declaration,
+    prefix_len: usize,
+    mapping: &ParameterMapping,
+) -> ParameterMapping {
+    let mut new_parameter_mapping = ParameterMapping::new();
+    for pm in &mapping.parameter_maps {
+        let prefix = decl.params[..prefix_len]
+            .iter()
+            .map(|p| pm[p].as_str())
+            .collect::<Vec<_>>();
+        'rows: for row in table.prefix(&prefix) {
+            let mut new_pm = pm.clone();
+            for (column, p) in decl.params.iter().enumerate().skip(prefix_len) {
+                match new_pm.get(p) {
+                    Some(value) if value != row.get(column) => continue 'rows,
+                    Some(_) => {}
+                    None => {
+                        new_pm.insert(p.clone(), row.get(column).to_string());
+                    }
+                }
+            }
+            new_parameter_mapping.parameter_maps.insert(new_pm);
+        }
+    }
+    new_parameter_mapping.parameter_keys = mapping
+        .parameter_keys
+        .iter()
+        .chain(decl.params.iter())
+        .cloned()
+        .collect();
+    new_parameter_mapping
+}
+
+fn get_output_fact(rule: &Rule, pm: &BTreeMap<String, String>) -> Fact {
+    Fact {
+        name: rule.head.name.clone(),
+        params: rule
+            .head
+            .params
+            .iter()
+            .map(|p| pm.get(p).unwrap().clone())
+            .collect(),
+    }
+}
+
+// the body facts that a parameter mapping of the rule was joined from
+fn get_body_facts(rule: &Rule, pm: &BTreeMap<String, String>) -> Vec<Fact> {
+    rule.body
+        .iter()
+        .filter(|decl| !is_external(&decl.name))
+        .map(|decl| Fact {
+            name: decl.name.clone(),
+            params: decl
                 .params
                 .iter()
                 .map(|p| pm.get(p).unwrap().clone())
                 .collect(),
+        })
+        .collect()
+}
+
+// The result of joining the body of a rule in one join order
+pub(crate) struct VariantResult {
+    // None if one of the body relations is empty
+    pub(crate) mapping: Option<ParameterMapping>,
+    // the number of parameter mappings after each join
+    pub(crate) sizes: Vec<usize>,
+}
+
+// Joins the body declarations of a rule (or constraint) in the order of `steps`, the first
+// one reading from the delta if it is marked so. `partition` is (index, count): only that chunk of the first
+// declaration's facts is read, so that large joins can be split between threads.
+// External relations are part of the full database, and of the delta when
+// `external_delta` is set. Returns None if the first declaration has nothing in the delta.
+pub(crate) fn evaluate_variant(
+    body: &[Declaration],
+    steps: &[JoinStep],
+    externals: &Externals,
+    full: &Database,
+    delta: &Database,
+    external_delta: bool,
+    partition: (usize, usize),
+) -> Option<VariantResult> {
+    let delta_name = &body[steps[0].body_idx].name;
+    let external = external_delta && externals.get_relation(delta_name).is_some();
+    if !external && !delta.tables.contains_key(delta_name) {
+        return None;
+    }
+    let (partition_idx, num_partitions) = partition;
+    let mut current_parameter_mapping = ParameterMapping::new();
+    let mut sizes = vec![];
+    for (step_idx, step) in steps.iter().enumerate() {
+        let decl = &body[step.body_idx];
+        if let Some(external) = externals.get(&decl.name) {
+            current_parameter_mapping = call_external(external, decl, &current_parameter_mapping);
+            sizes.push(current_parameter_mapping.parameter_maps.len());
+            continue;
+        }
+        let source = if step.delta { delta } else { full };
+        let mut joined = source.tables.get(&decl.name).map(|table| {
+            // the leading parameters bound by the earlier steps are looked up in the sorted
+            // runs, unless there are more bindings than facts to scan
+            let prefix_len = decl
+                .params
+                .iter()
+                .take_while(|p| current_parameter_mapping.parameter_keys.contains(*p))
+                .count();
+            if step_idx != 0
+                && prefix_len != 0
+                && current_parameter_mapping.parameter_maps.len() <= table.len()
+            {
+                return probe_table(table, decl, prefix_len, &current_parameter_mapping);
+            }
+            let rows = if step_idx == 0 {
+                partition_range(table.len(), partition_idx, num_partitions)
+            } else {
+                0..table.len()
+            };
+            let rows = if step.delta {
+                full.delta_rows(table, rows)
+            } else {
+                table.rows(rows)
+            };
+            let parameter_mapping = get_parameter_mapping(rows, &decl.params);
+            join_parameter_mapping(&current_parameter_mapping, &parameter_mapping)
         });
+        if let Some(relation) = externals.get_relation(&decl.name) {
+            // the tuples of an external relation are not partitioned, the first chunk
+            // reads all of them
+            if (!step.delta || external_delta) && (step_idx != 0 || partition_idx == 0) {
+                let external_mapping =
+                    join_external_relation(relation, decl, &current_parameter_mapping);
+                joined = Some(match joined {
+                    Some(mut mapping) => {
+                        mapping
+                            .parameter_maps
+                            .extend(external_mapping.parameter_maps);
+                        mapping
+                    }
+                    None => external_mapping,
+                });
+            }
+        }
+        let Some(mapping) = joined else {
+            return Some(VariantResult {
+                mapping: None,
+                sizes,
+            });
+        };
+        current_parameter_mapping = mapping;
+        sizes.push(current_parameter_mapping.parameter_maps.len());
     }
-    facts.into_iter().collect()
+    Some(VariantResult {
+        mapping: Some(current_parameter_mapping),
+        sizes,
+    })
 }
 
-pub fn run_datalog(program: &Program, input: Vec<Fact>) -> Result<Vec<Fact>, String> {
-    verify_facts(program, &input)?;
+// Chunk `index` of `count` roughly equal chunks of `0..len`
+pub(crate) fn partition_range(len: usize, index: usize, count: usize) -> Range<usize> {
+    let chunk_size = len.div_ceil(count);
+    let start = (index * chunk_size).min(len);
+    start..(start + chunk_size).min(len)
+}
+
+// Semi-naive evaluation of a single rule: each body declaration takes a turn reading
+// from the delta (the facts that are new since the last round) while the others read
+// from the full database, so only derivations using at least one new fact are made.
+// The joins happen in the order chosen by the plan.
+// The number of parameter mappings after each join is pushed to `join_sizes`.
+fn get_rule_parameter_mappings(
+    rule: &Rule,
+    rule_plan: &RulePlan,
+    externals: &Externals,
+    full: &Database,
+    delta: &Database,
+    external_delta: bool,
+    join_sizes: &mut Vec<Vec<usize>>,
+) -> Vec<ParameterMapping> {
+    let mut mappings = vec![];
+    for steps in &rule_plan.variants {
+        let result = if rule_plan.leapfrog {
+            leapfrog_variant(rule, steps, full, delta, (0, 1))
+        } else {
+            evaluate_variant(
+                &rule.body,
+                steps,
+                externals,
+                full,
+                delta,
+                external_delta,
+                (0, 1),
+            )
+        };
+        let Some(result) = result else {
+            continue;
+        };
+        join_sizes.push(result.sizes);
+        mappings.extend(result.mapping);
+    }
+    mappings
+}
 
-    // the new frontier consists of facts just recently added
-    let mut frontier = Database {
-        tables: HashMap::new(),
+// Joins the parameter mappings with an external relation. The relation is probed once per
+// mapping with the parameters it binds, or scanned if the mappings bind none of them.
+pub(crate) fn join_external_relation(
+    relation: &dyn ExternalRelation,
+    decl: &Declaration,
+    mapping: &ParameterMapping,
+) -> ParameterMapping {
+    let repeated = repeated_columns(&decl.params);
+    let consistent = |tuple: &Vec<String>| {
+        repeated
+            .iter()
+            .all(|(first, column)| tuple[*first] == tuple[*column])
     };
-    extend_database(&mut frontier, &input);
-    // semi-naive evaluation runs new X old, new X new, and old X new to join facts together
-    let mut facts_hashset: HashSet<Fact> = HashSet::from_iter(input.iter().cloned());
-
-    loop {
-        let mut new_facts = vec![];
-        for rule in &program.rules {
-            let mut good_rule = true;
-            let mut current_parameter_mapping = ParameterMapping::new();
-            for decl in rule.body.iter() {
-                if let Some(table) = frontier.tables.get(&decl.name) {
-                    let parameter_mapping = get_parameter_mapping(table, &decl.params);
-                    current_parameter_mapping =
-                        join_parameter_mapping(&current_parameter_mapping, &parameter_mapping);
-                } else {
-                    good_rule = false;
-                    break;
+    if !decl
+        .params
+        .iter()
+        .any(|p| mapping.parameter_keys.contains(p))
+    {
+        let mut scanned = ParameterMapping::new();
+        for tuple in relation.scan().filter(consistent) {
+            scanned
+                .parameter_maps
+                .insert(decl.params.iter().cloned().zip(tuple).collect());
+        }
+        scanned.parameter_keys = decl.params.iter().cloned().collect();
+        return join_parameter_mapping(mapping, &scanned);
+    }
+
+    let mut new_parameter_mapping = ParameterMapping::new();
+    for pm in &mapping.parameter_maps {
+        let bound = decl
+            .params
+            .iter()
+            .map(|p| pm.get(p).map(|value| value.as_str()))
+            .collect::<Vec<_>>();
+        for tuple in relation.probe(&bound).filter(consistent) {
+            let mut new_pm = pm.clone();
+            new_pm.extend(decl.params.iter().cloned().zip(tuple));
+            new_parameter_mapping.parameter_maps.insert(new_pm);
+        }
+    }
+    new_parameter_mapping.parameter_keys = mapping
+        .parameter_keys
+        .iter()
+        .chain(decl.params.iter())
+        .cloned()
+        .collect();
+    new_parameter_mapping
+}
+
+// Filters the parameter mappings with an external predicate, or extends them with the
+// result of an external functor (filtering if the result parameter is already bound)
+pub(crate) fn call_external(
+    external: &External,
+    decl: &Declaration,
+    mapping: &ParameterMapping,
+) -> ParameterMapping {
+    let mut new_parameter_mapping = ParameterMapping::new();
+    for pm in &mapping.parameter_maps {
+        let args = decl.params[..external.arity]
+            .iter()
+            .map(|p| pm.get(p).unwrap().as_str())
+            .collect::<Vec<_>>();
+        let Some(result) = external.call(&args) else {
+            continue;
+        };
+        let mut new_pm = pm.clone();
+        if external.kind == ExternalKind::Functor {
+            let param = &decl.params[external.arity];
+            match pm.get(param) {
+                Some(value) if *value != result => continue,
+                Some(_) => {}
+                None => {
+                    new_pm.insert(param.clone(), result);
                 }
             }
-            if good_rule {
-                let output_facts = get_output_fact(rule, &current_parameter_mapping);
-                for new_fact in output_facts {
-                    if !facts_hashset.contains(&new_fact) {
+        }
+        new_parameter_mapping.parameter_maps.insert(new_pm);
+    }
+    new_parameter_mapping.parameter_keys = mapping
+        .parameter_keys
+        .iter()
+        .chain(decl.params.iter())
+        .cloned()
+        .collect();
+    new_parameter_mapping
+}
+
+pub(crate) fn apply_rule(
+    program: &Program,
+    plan: &Plan,
+    rule_plan: &RulePlan,
+    full: &Database,
+    delta: &Database,
+) -> HashSet<Fact> {
+    let rule = &program.rules[rule_plan.rule];
+    get_rule_parameter_mappings(
+        rule,
+        rule_plan,
+        &plan.externals,
+        full,
+        delta,
+        false,
+        &mut vec![],
+    )
+    .iter()
+    .flat_map(|mapping| mapping.parameter_maps.iter())
+    .map(|pm| get_output_fact(rule, pm))
+    .collect()
+}
+
+// Everything run_semi_naive can record about the evaluation besides the facts themselves
+#[derive(Default)]
+pub(crate) struct Recorder<'a> {
+    pub(crate) provenance: Option<Provenance>,
+    pub(crate) profile: Option<Profile>,
+    pub(crate) progress: Option<&'a mut dyn FnMut(&Progress)>,
+}
+
+// Resource limits on an evaluation
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Limits {
+    pub(crate) max_iterations: Option<usize>,
+    pub(crate) max_facts: Option<usize>,
+    pub(crate) deadline: Option<(Instant, Duration)>,
+    pub(crate) cancel: Option<CancellationToken>,
+    // the number of threads the rules of an iteration are evaluated on, 0 and 1 both
+    // evaluate on the calling thread
+    pub(crate) workers: usize,
+    // see RunOptions::partition_facts
+    pub(crate) partition_facts: Option<usize>,
+}
+
+// run_semi_naive stopped before reaching a fixpoint
+pub(crate) enum Stopped {
+    Limit {
+        limit: Limit,
+        // relations that got new facts in the last iteration before stopping
+        growing: Vec<String>,
+    },
+    Cancelled,
+    // a rule derived a value that is not in the lattice of its relation
+    Invalid(String),
+}
+
+// The same messages as RunError
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stopped::Limit { limit, growing } => write!(
+                f,
+                "{} exceeded, still growing: {}",
+                limit,
+                growing.join(", ")
+            ),
+            Stopped::Cancelled => write!(f, "evaluation cancelled"),
+            Stopped::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// The relations of the new facts and of the frontier. Before the first iteration of a stratum
+// there is no frontier yet, and every relation of the stratum still has to grow.
+fn get_growing_relations(
+    stratum: &Stratum,
+    new_facts: &[Fact],
+    frontier: Option<&Database>,
+) -> Vec<String> {
+    let previous = match frontier {
+        Some(frontier) => frontier.tables.keys().cloned().collect::<Vec<_>>(),
+        None => stratum.relations.clone(),
+    };
+    let mut growing = new_facts
+        .iter()
+        .map(|fact| fact.name.clone())
+        .chain(previous)
+        .collect::<Vec<_>>();
+    growing.sort();
+    growing.dedup();
+    growing
+}
+
+// How a fact that extend_database added besides the inserted ones came about
+fn merged_derivation(database: &Database, fact: &Fact) -> Derivation {
+    if database.is_eqrel(&fact.name) {
+        Derivation::Equivalence
+    } else {
+        Derivation::LatticeJoin
+    }
+}
+
+// Runs the rules until no new facts are derived, one stratum of the plan at a time.
+// `delta` holds the facts that were just added to `database`, everything derivable
+// without them must already be there. `first_run` is set when the rules have never been
+// run on `database`, so that the tuples of external relations are new as well.
+// Returns the derived facts that were not in the database before, or why evaluation
+// stopped early. When it stops, `database` holds everything derived up to that point.
+pub(crate) fn run_semi_naive(
+    program: &Program,
+    plan: &Plan,
+    database: &mut Database,
+    delta: Vec<Fact>,
+    first_run: bool,
+    recorder: &mut Recorder,
+    limits: &Limits,
+) -> Result<Vec<Fact>, Stopped> {
+    let mut derived = vec![];
+    let mut iteration = 0;
+    // every fact new to this run, since the rules of a stratum have to see the new
+    // facts of all earlier strata as well as the ones of their own
+    let mut new_in_run = Database::new();
+    extend_database(&mut new_in_run, &delta);
+    // reading the tuples of external relations again after the first run would only derive
+    // facts that are already there
+    let external_delta = first_run;
+
+    for (stratum_idx, stratum) in plan.strata.iter().enumerate() {
+        let mut stratum_derived = vec![];
+        let mut frontier = None;
+        loop {
+            let frontier_db = frontier.as_ref().unwrap_or(&new_in_run);
+            let mut new_facts = vec![];
+            // the facts of new_facts, which are only added to the database at the end of
+            // the iteration
+            let mut new_facts_set = HashSet::new();
+            iteration += 1;
+            if let Some(max_iterations) = limits.max_iterations {
+                if iteration > max_iterations {
+                    return Err(Stopped::Limit {
+                        limit: Limit::Iterations(max_iterations),
+                        growing: get_growing_relations(stratum, &[], frontier.as_ref()),
+                    });
+                }
+            }
+            if let Some(profile) = recorder.profile.as_mut() {
+                profile.start_iteration(stratum_idx);
+            }
+            // with more than one worker every rule is evaluated up front, and the new
+            // facts are then merged in the same order as on a single thread
+            let mut evaluations = (limits.workers > 1)
+                .then(|| {
+                    evaluate_rules_parallel(
+                        program,
+                        plan,
+                        &stratum.rules,
+                        database,
+                        frontier_db,
+                        external_delta && frontier.is_none(),
+                        limits,
+                    )
+                })
+                .map(|evaluations| evaluations.into_iter());
+            for rule_plan in &stratum.rules {
+                let rule = &program.rules[rule_plan.rule];
+                let start = recorder.profile.as_ref().map(|_| Instant::now());
+                let mut join_sizes = vec![];
+                let mut evaluation_time_ns = 0;
+                let mut num_new = 0;
+                let mut num_duplicate = 0;
+                let mappings = match evaluations.as_mut().and_then(|e| e.next()) {
+                    Some(evaluation) => {
+                        join_sizes = evaluation.join_sizes;
+                        evaluation_time_ns = evaluation.time_ns;
+                        evaluation.mappings
+                    }
+                    None => get_rule_parameter_mappings(
+                        rule,
+                        rule_plan,
+                        &plan.externals,
+                        database,
+                        frontier_db,
+                        external_delta && frontier.is_none(),
+                        &mut join_sizes,
+                    ),
+                };
+                for mapping in mappings {
+                    for pm in mapping.parameter_maps.iter() {
+                        let new_fact = get_output_fact(rule, pm);
+                        database
+                            .check_lattice_values([&new_fact])
+                            .map_err(Stopped::Invalid)?;
+                        if database.subsumes(&new_fact) || new_facts_set.contains(&new_fact) {
+                            num_duplicate += 1;
+                            continue;
+                        }
+                        if let Some(provenance) = recorder.provenance.as_mut() {
+                            provenance.record(
+                                new_fact.clone(),
+                                Derivation::Rule {
+                                    rule: rule_plan.rule,
+                                    body: get_body_facts(rule, pm),
+                                },
+                            );
+                        }
+                        num_new += 1;
+                        new_facts_set.insert(new_fact.clone());
                         new_facts.push(new_fact);
                     }
                 }
+                if let (Some(profile), Some(start)) = (recorder.profile.as_mut(), start) {
+                    profile.record_rule(RuleProfile {
+                        rule: rule_plan.rule,
+                        time_ns: evaluation_time_ns + start.elapsed().as_nanos() as u64,
+                        join_sizes,
+                        new_facts: num_new,
+                        duplicate_facts: num_duplicate,
+                    });
+                }
+
+                let limit = match (limits.max_facts, limits.deadline) {
+                    (Some(max_facts), _) if database.len() + new_facts.len() > max_facts => {
+                        Some(Limit::Facts(max_facts))
+                    }
+                    (_, Some((deadline, timeout))) if Instant::now() > deadline => {
+                        Some(Limit::Timeout(timeout))
+                    }
+                    _ => None,
+                };
+                let cancelled = limits
+                    .cancel
+                    .as_ref()
+                    .is_some_and(|cancel| cancel.is_cancelled());
+                if cancelled || limit.is_some() {
+                    extend_database(database, &new_facts);
+                }
+                if cancelled {
+                    return Err(Stopped::Cancelled);
+                }
+                if let Some(limit) = limit {
+                    return Err(Stopped::Limit {
+                        limit,
+                        growing: get_growing_relations(stratum, &new_facts, frontier.as_ref()),
+                    });
+                }
+            }
+            if !new_facts.is_empty() {
+                let inserted = extend_database(database, &new_facts);
+                // the pairs of .eqrel relations imply further pairs, and lattice-valued
+                // relations keep the join of the values of a key instead of the derived
+                // value, so the new facts are the inserted ones
+                if database.merges_facts() {
+                    let merged = inserted
+                        .iter()
+                        .filter(|fact| !new_facts_set.contains(*fact))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    let inserted = inserted.into_iter().collect::<HashSet<_>>();
+                    new_facts.retain(|fact| inserted.contains(fact));
+                    for fact in merged {
+                        if let Some(provenance) = recorder.provenance.as_mut() {
+                            provenance.record(fact.clone(), merged_derivation(database, &fact));
+                        }
+                        new_facts.push(fact);
+                    }
+                }
+            }
+            if let Some(profile) = recorder.profile.as_mut() {
+                profile.finish_iteration(database.sizes());
             }
+            if let Some(progress) = recorder.progress.as_mut() {
+                progress(&Progress {
+                    iteration,
+                    stratum: stratum_idx,
+                    relation_sizes: database.sizes(),
+                });
+            }
+            if new_facts.is_empty() || !stratum.recursive {
+                stratum_derived.extend(new_facts);
+                break;
+            }
+            let mut next_frontier = Database::new();
+            extend_database(&mut next_frontier, &new_facts);
+            frontier = Some(next_frontier);
+            stratum_derived.extend(new_facts);
         }
-        if new_facts.is_empty() {
-            break;
+        extend_database(&mut new_in_run, &stratum_derived);
+        derived.extend(stratum_derived);
+    }
+    Ok(derived)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    // record how every derived fact was first derived
+    pub provenance: bool,
+    // collect per rule and per iteration statistics
+    pub profile: bool,
+    // stop after this many iterations, counted over all strata
+    pub max_iterations: Option<usize>,
+    // stop once the database holds more facts than this, input facts included
+    pub max_facts: Option<usize>,
+    // stop once evaluation has run for this long
+    // (std::time::Instant is not available on wasm32-unknown-unknown, so leave this unset there)
+    pub timeout: Option<Duration>,
+    // return the input relations as well, not only the .output ones
+    pub all_relations: bool,
+    // sort the returned facts (Database::sort) so that the output is the same on every run
+    pub sorted: bool,
+    // the external predicates and functors the program calls as `@name(...)`
+    pub externals: Externals,
+    // evaluate the rules of every iteration on this many threads (0 and 1 evaluate on the
+    // calling thread). The result is the same for any number of workers.
+    pub workers: usize,
+    // with more than one worker, the join orders whose delta has at least this many facts
+    // are split between the workers (4096 if unset)
+    pub partition_facts: Option<usize>,
+    // how the bodies of rules are joined, see JoinStrategy
+    pub join_strategy: JoinStrategy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Iterations(usize),
+    Facts(usize),
+    Timeout(Duration),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Iterations(max) => write!(f, "iteration limit of {}", max),
+            Limit::Facts(max) => write!(f, "fact limit of {}", max),
+            Limit::Timeout(timeout) => write!(f, "timeout of {:?}", timeout),
         }
-        facts_hashset.extend(new_facts.iter().cloned());
-        extend_database(&mut frontier, &new_facts);
     }
+}
+
+#[derive(Debug, Error)]
+pub enum RunError {
+    // the program or the input facts are not valid
+    #[error("{0}")]
+    Invalid(String),
+    // evaluation hit one of the limits in RunOptions before reaching a fixpoint
+    #[error("{limit} exceeded, still growing: {}", growing.join(", "))]
+    LimitExceeded {
+        limit: Limit,
+        // everything derived before the limit was hit
+        partial: Database,
+        growing: Vec<String>,
+    },
+    // the CancellationToken passed to run_datalog_with_progress was cancelled
+    #[error("evaluation cancelled")]
+    Cancelled {
+        // everything derived before the evaluation was cancelled
+        partial: Database,
+    },
+    // the program evaluated to a fixpoint, but some of its constraints or assertions do not
+    // hold on it
+    #[error("{}", violations.iter().map(|v| v.to_string()).collect::<Vec<_>>().join("\n"))]
+    ConstraintViolated {
+        violations: Vec<Violation>,
+        // the relations that would have been returned
+        database: Database,
+    },
+}
+
+impl From<String> for RunError {
+    fn from(message: String) -> RunError {
+        RunError::Invalid(message)
+    }
+}
+
+#[derive(Debug)]
+pub struct RunOutput {
+    pub database: Database,
+    pub provenance: Option<Provenance>,
+    pub profile: Option<Profile>,
+}
+
+// Evaluates the program on the input facts and returns its .output relations
+pub fn run_datalog(program: &Program, input: Vec<Fact>) -> Result<Database, RunError> {
+    Ok(run_datalog_with_options(program, input, &RunOptions::default())?.database)
+}
 
-    // println!("{:?}", facts_hashset);
-    Ok(facts_hashset.into_iter().collect())
+pub fn run_datalog_with_options(
+    program: &Program,
+    input: Vec<Fact>,
+    options: &RunOptions,
+) -> Result<RunOutput, RunError> {
+    run_datalog_with_progress(
+        program,
+        input,
+        options,
+        |_: &Progress| {},
+        &CancellationToken::new(),
+    )
 }
+
+// Like run_datalog_with_options, but calls `progress` after every iteration and stops with
+// RunError::Cancelled once `cancel` is cancelled. `progress` runs on the evaluating thread,
+// so it can also cancel the evaluation itself, e.g. when a user pressed a stop button.
+pub fn run_datalog_with_progress(
+    program: &Program,
+    input: Vec<Fact>,
+    options: &RunOptions,
+    mut progress: impl FnMut(&Progress),
+    cancel: &CancellationToken,
+) -> Result<RunOutput, RunError> {
+    verify_facts(program, &input)?;
+    let plan = plan_program_with_externals(program, &options.externals)?
+        .with_join_strategy(options.join_strategy);
+
+    let mut database = Database::for_program(program, &plan.externals);
+    database.check_lattice_values(&input)?;
+    // without duplicates, with the pairs implied by the input pairs of .eqrel relations and
+    // the joined values of lattice-valued relations
+    let given = input;
+    let input = extend_database(&mut database, &given);
+    let mut recorder = Recorder {
+        progress: Some(&mut progress),
+        ..Recorder::default()
+    };
+    if options.provenance {
+        let mut provenance = Provenance::new(program);
+        let given = given.iter().collect::<HashSet<_>>();
+        for fact in &input {
+            let derivation = if given.contains(fact) {
+                Derivation::Input
+            } else {
+                merged_derivation(&database, fact)
+            };
+            provenance.record(fact.clone(), derivation);
+        }
+        // the given pairs of .eqrel relations that did not change a class are not inserted
+        for fact in given.iter().filter(|fact| database.is_eqrel(&fact.name)) {
+            provenance.record((*fact).clone(), Derivation::Input);
+        }
+        recorder.provenance = Some(provenance);
+    }
+    if options.profile {
+        recorder.profile = Some(Profile::new(program));
+    }
+    let limits = Limits {
+        max_iterations: options.max_iterations,
+        max_facts: options.max_facts,
+        deadline: options
+            .timeout
+            .map(|timeout| (Instant::now() + timeout, timeout)),
+        cancel: Some(cancel.clone()),
+        workers: options.workers,
+        partition_facts: options.partition_facts,
+    };
+    let result = run_semi_naive(
+        program,
+        &plan,
+        &mut database,
+        input,
+        true,
+        &mut recorder,
+        &limits,
+    );
+    let returned_relations = program
+        .decls
+        .iter()
+        .filter(|decl| options.all_relations || decl.kind == DeclKind::Output)
+        .map(|decl| decl.name.as_str())
+        .collect::<HashSet<_>>();
+    if let Err(stopped) = result {
+        let mut partial = database;
+        partial.retain_relations(&returned_relations);
+        if options.sorted {
+            partial.sort();
+        }
+        return Err(match stopped {
+            Stopped::Limit { limit, growing } => RunError::LimitExceeded {
+                limit,
+                partial,
+                growing,
+            },
+            Stopped::Cancelled => RunError::Cancelled { partial },
+            Stopped::Invalid(message) => RunError::Invalid(message),
+        });
+    }
+
+    let violations = check_constraints(program, &plan, &database);
+    database.retain_relations(&returned_relations);
+    if options.sorted {
+        database.sort();
+    }
+    if !violations.is_empty() {
+        return Err(RunError::ConstraintViolated {
+            violations,
+            database,
+        });
+    }
+    Ok(RunOutput {
+        database,
+        provenance: recorder.provenance,
+        profile: recorder.profile,
+    })
+}
+#[cfg(test)]
 mod tests {
-    use super::{join_parameter_mapping, ParameterMapping};
+    use super::{
+        get_parameter_mapping, join_parameter_mapping, run_datalog_with_options,
+        run_datalog_with_progress, Limit, ParameterMapping, RunError, RunOptions,
+    };
+    use crate::database::Table;
+    use crate::parse::{parse_fact_vector, parse_program, Token};
+    use crate::plan::JoinStrategy;
+    use crate::progress::CancellationToken;
+    use logos::Logos;
     use std::collections::{BTreeMap, HashSet};
 
     #[test]
@@ -272,4 +1026,188 @@ mod tests {
             ("d_key".to_string(), "d_value2".to_string()),
         ])));
     }
+
+    #[test]
+    fn test_join_cross_product() {
+        let mapping = |key: &str, values: &[&str]| ParameterMapping {
+            parameter_maps: values
+                .iter()
+                .map(|value| BTreeMap::from([(key.to_string(), value.to_string())]))
+                .collect(),
+            parameter_keys: HashSet::from([key.to_string()]),
+        };
+        // without keys in common every pair of mappings is joined
+        let c = join_parameter_mapping(&mapping("x", &["1", "2"]), &mapping("y", &["3", "4", "5"]));
+        assert_eq!(c.parameter_maps.len(), 6);
+        assert_eq!(c.parameter_keys.len(), 2);
+        assert!(c.parameter_maps.contains(&BTreeMap::from([
+            ("x".to_string(), "2".to_string()),
+            ("y".to_string(), "4".to_string()),
+        ])));
+
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl a(x) .input;
+            .decl b(y) .input;
+            .decl pair(x, y) .output;
+            .rule pair(x, y) :- 2 a(x), b(y);
+            "#,
+        ))
+        .unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer("3 a(p); a(q); b(r);")).unwrap();
+        let database = run_datalog_with_options(&program, facts, &RunOptions::default())
+            .unwrap()
+            .database;
+        assert_eq!(database.len(), 2);
+    }
+
+    #[test]
+    fn test_repeated_variables() {
+        let mut table = Table::new("edge", 2);
+        let pair = |x: &str, y: &str| vec![x.to_string(), y.to_string()];
+        table.insert(vec![pair("a", "a"), pair("a", "b"), pair("b", "b")]);
+        // edge(x, x) only matches the rows with the same value in both columns
+        let mapping = get_parameter_mapping(table.rows(0..table.len()), &pair("x", "x"));
+        assert_eq!(
+            mapping.parameter_maps,
+            HashSet::from([
+                BTreeMap::from([("x".to_string(), "a".to_string())]),
+                BTreeMap::from([("x".to_string(), "b".to_string())]),
+            ])
+        );
+
+        // as the first declaration of a body, and probed with x already bound
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl node(x) .input;
+            .decl self_loop(x) .output;
+            .decl on_loop(x) .output;
+            .rule self_loop(x) :- 1 edge(x, x);
+            .rule on_loop(x) :- 2 node(x), edge(x, x);
+            "#,
+        ))
+        .unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer(
+            "5 edge(a, a); edge(a, b); edge(c, c); node(a); node(b);",
+        ))
+        .unwrap();
+        for join_strategy in [JoinStrategy::Hash, JoinStrategy::Leapfrog] {
+            let options = RunOptions {
+                sorted: true,
+                join_strategy,
+                ..RunOptions::default()
+            };
+            let database = run_datalog_with_options(&program, facts.clone(), &options)
+                .unwrap()
+                .database;
+            let output = database
+                .facts()
+                .map(|fact| fact.to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(output, vec!["on_loop(a)", "self_loop(a)", "self_loop(c)"]);
+        }
+    }
+
+    #[test]
+    fn test_iteration_limit() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl reachable(x, y) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            "#,
+        ))
+        .unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer(
+            "5 edge(a, b); edge(b, c); edge(c, d); edge(d, e); edge(e, f);",
+        ))
+        .unwrap();
+        let options = RunOptions {
+            max_iterations: Some(2),
+            ..RunOptions::default()
+        };
+        let Err(RunError::LimitExceeded {
+            limit,
+            partial,
+            growing,
+        }) = run_datalog_with_options(&program, facts.clone(), &options)
+        else {
+            panic!("the chain needs more than 2 iterations");
+        };
+        assert_eq!(limit, Limit::Iterations(2));
+        assert_eq!(growing, vec!["reachable".to_string()]);
+        assert!(partial.relation("edge").next().is_none());
+        assert!(partial.len() > facts.len());
+
+        let options = RunOptions {
+            max_facts: Some(100),
+            ..RunOptions::default()
+        };
+        assert!(run_datalog_with_options(&program, facts.clone(), &options).is_ok());
+
+        // reachable reaches its fixpoint in the 6th iteration, so a limit of 6 stops right
+        // before the first iteration of the stratum of linked
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl reachable(x, y) .output;
+            .decl linked(x) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            .rule linked(x) :- 1 reachable(x, y);
+            "#,
+        ))
+        .unwrap();
+        let options = RunOptions {
+            max_iterations: Some(6),
+            ..RunOptions::default()
+        };
+        let Err(RunError::LimitExceeded {
+            growing, partial, ..
+        }) = run_datalog_with_options(&program, facts, &options)
+        else {
+            panic!("linked needs a 7th iteration");
+        };
+        assert_eq!(growing, vec!["linked".to_string()]);
+        assert!(partial.relation("linked").next().is_none());
+    }
+
+    #[test]
+    fn test_cancel_from_progress() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl reachable(x, y) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            "#,
+        ))
+        .unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer(
+            "5 edge(a, b); edge(b, c); edge(c, d); edge(d, e); edge(e, f);",
+        ))
+        .unwrap();
+        let cancel = CancellationToken::new();
+        let mut iterations = vec![];
+        let result = run_datalog_with_progress(
+            &program,
+            facts,
+            &RunOptions::default(),
+            |progress| {
+                iterations.push(progress.iteration);
+                if progress.relation_sizes["reachable"] >= 9 {
+                    cancel.cancel();
+                }
+            },
+            &cancel,
+        );
+        // 5 + 4 facts after two iterations, the third one notices the cancellation
+        assert_eq!(iterations, vec![1, 2]);
+        let Err(RunError::Cancelled { partial }) = result else {
+            panic!("evaluation should have been cancelled");
+        };
+        assert!(partial.len() >= 9);
+    }
 }
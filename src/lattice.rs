@@ -0,0 +1,342 @@
+use std::any::Any;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Lattice-valued relations: a relation declared as `.decl const_val(i, x, v: lattice<L>)`
+// holds at most one value of the lattice registered as `L` per key (the other parameters).
+// When rules derive more values for a key, the relation keeps their join, and a derived
+// value below the one already there is not new. A key without a value is at bottom, so
+// bottom itself is never stored.
+//
+// Values are stored in facts as strings, through FromStr and Display. The rules that derive
+// lattice values have to be monotone for the fixpoint to be the least one.
+pub trait Lattice: FromStr + fmt::Display + Sized + Send + Sync + 'static {
+    fn bottom() -> Self;
+
+    // The least upper bound of self and other
+    fn join(&self, other: &Self) -> Self;
+
+    // Whether self is below or equal to other
+    fn leq(&self, other: &Self) -> bool;
+}
+
+// The lattice of constant propagation: bottom (no value seen yet), a single constant, or top
+// (more than one value). Constants are any string but `top` and `bottom`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConstLattice {
+    Bottom,
+    Const(String),
+    Top,
+}
+
+impl FromStr for ConstLattice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConstLattice, String> {
+        Ok(match s {
+            "bottom" => ConstLattice::Bottom,
+            "top" => ConstLattice::Top,
+            value => ConstLattice::Const(value.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for ConstLattice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConstLattice::Bottom => write!(f, "bottom"),
+            ConstLattice::Const(value) => write!(f, "{}", value),
+            ConstLattice::Top => write!(f, "top"),
+        }
+    }
+}
+
+impl Lattice for ConstLattice {
+    fn bottom() -> ConstLattice {
+        ConstLattice::Bottom
+    }
+
+    fn join(&self, other: &ConstLattice) -> ConstLattice {
+        match (self, other) {
+            (ConstLattice::Bottom, value) | (value, ConstLattice::Bottom) => value.clone(),
+            (ConstLattice::Const(a), ConstLattice::Const(b)) if a == b => self.clone(),
+            _ => ConstLattice::Top,
+        }
+    }
+
+    fn leq(&self, other: &ConstLattice) -> bool {
+        match (self, other) {
+            (ConstLattice::Bottom, _) | (_, ConstLattice::Top) => true,
+            (ConstLattice::Const(a), ConstLattice::Const(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+// A value of a registered Lattice, parsed once when it gets into the database
+pub(crate) type Value = Arc<dyn Any + Send + Sync>;
+
+type Parse = Arc<dyn Fn(&str) -> Option<Value> + Send + Sync>;
+type Join = Arc<dyn Fn(&Value, &Value) -> Value + Send + Sync>;
+type Leq = Arc<dyn Fn(&Value, &Value) -> bool + Send + Sync>;
+type Show = Arc<dyn Fn(&Value) -> String + Send + Sync>;
+
+// A registered Lattice, working on its values behind Value. Values are checked before
+// they get into the database, so the values passed to join and leq are of the lattice.
+#[derive(Clone)]
+pub(crate) struct LatticeOps {
+    pub(crate) name: String,
+    parse: Parse,
+    join: Join,
+    leq: Leq,
+    show: Show,
+    bottom: Value,
+}
+
+fn get<L: Lattice>(value: &Value) -> &L {
+    value.downcast_ref::<L>().unwrap()
+}
+
+impl LatticeOps {
+    pub(crate) fn new<L: Lattice>(name: &str) -> LatticeOps {
+        LatticeOps {
+            name: name.to_string(),
+            parse: Arc::new(|value| Some(Arc::new(value.parse::<L>().ok()?) as Value)),
+            join: Arc::new(|a, b| Arc::new(get::<L>(a).join(get::<L>(b)))),
+            leq: Arc::new(|a, b| get::<L>(a).leq(get::<L>(b))),
+            show: Arc::new(|value| get::<L>(value).to_string()),
+            bottom: Arc::new(L::bottom()),
+        }
+    }
+
+    pub(crate) fn parse(&self, value: &str) -> Result<Value, String> {
+        (self.parse)(value).ok_or_else(|| {
+            format!(
+                "{} is not a valid lattice value of lattice {}",
+                value, self.name
+            )
+        })
+    }
+
+    pub(crate) fn check(&self, value: &str) -> Result<(), String> {
+        self.parse(value).map(|_| ())
+    }
+
+    pub(crate) fn join(&self, a: &Value, b: &Value) -> Value {
+        (self.join)(a, b)
+    }
+
+    pub(crate) fn leq(&self, a: &Value, b: &Value) -> bool {
+        (self.leq)(a, b)
+    }
+
+    pub(crate) fn show(&self, value: &Value) -> String {
+        (self.show)(value)
+    }
+
+    pub(crate) fn bottom(&self) -> &Value {
+        &self.bottom
+    }
+}
+
+impl fmt::Debug for LatticeOps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LatticeOps").field(&self.name).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConstLattice, Lattice, LatticeOps};
+    use crate::external::Externals;
+    use crate::implem::{run_datalog_with_options, RunError, RunOptions};
+    use crate::parse::{parse_program, Fact, Token};
+    use crate::plan::JoinStrategy;
+    use logos::Logos;
+    use std::fmt;
+    use std::str::FromStr;
+
+    // The distance of shortest paths: lower is more information, bottom is no path
+    #[derive(Debug, PartialEq)]
+    struct MinDistance(Option<u32>);
+
+    impl FromStr for MinDistance {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<MinDistance, String> {
+            match s {
+                "inf" => Ok(MinDistance(None)),
+                s => s
+                    .parse()
+                    .map(|d| MinDistance(Some(d)))
+                    .map_err(|_| s.to_string()),
+            }
+        }
+    }
+
+    impl fmt::Display for MinDistance {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.0 {
+                Some(d) => write!(f, "{}", d),
+                None => write!(f, "inf"),
+            }
+        }
+    }
+
+    impl Lattice for MinDistance {
+        fn bottom() -> MinDistance {
+            MinDistance(None)
+        }
+
+        fn join(&self, other: &MinDistance) -> MinDistance {
+            match (self.0, other.0) {
+                (Some(a), Some(b)) => MinDistance(Some(a.min(b))),
+                (a, b) => MinDistance(a.or(b)),
+            }
+        }
+
+        fn leq(&self, other: &MinDistance) -> bool {
+            match (self.0, other.0) {
+                (Some(a), Some(b)) => b <= a,
+                (a, _) => a.is_none(),
+            }
+        }
+    }
+
+    const SHORTEST_PATHS: &str = r#"
+        .decl start(x, d) .input;
+        .decl edge(x, y, w) .input;
+        .decl dist(x, d: lattice<MinDistance>) .output;
+        .rule dist(x, d) :- 1 start(x, d);
+        .rule dist(y, e) :- 3 dist(x, d), edge(x, y, w), @add(d, w, e);
+    "#;
+
+    fn externals() -> Externals {
+        let mut externals = Externals::new();
+        externals.lattice::<MinDistance>("MinDistance");
+        externals.lattice::<ConstLattice>("ConstLattice");
+        externals.functor("add", 2, |args| {
+            match (args[0].parse::<u32>(), args[1].parse::<u32>()) {
+                (Ok(a), Ok(b)) => (a + b).to_string(),
+                _ => "?".to_string(),
+            }
+        });
+        externals
+    }
+
+    // Facts given as the relation name followed by the parameters, since the parameters of
+    // facts in the .in format can't be numbers
+    fn facts(facts: &[&[&str]]) -> Vec<Fact> {
+        facts
+            .iter()
+            .map(|fact| Fact {
+                name: fact[0].to_string(),
+                params: fact[1..].iter().map(|param| param.to_string()).collect(),
+            })
+            .collect()
+    }
+
+    fn run(
+        src: &str,
+        facts: Vec<Fact>,
+        join_strategy: JoinStrategy,
+    ) -> Result<Vec<String>, String> {
+        let program = parse_program(&mut Token::lexer(src)).unwrap();
+        let options = RunOptions {
+            externals: externals(),
+            sorted: true,
+            join_strategy,
+            ..RunOptions::default()
+        };
+        // every expected error is a RunError::Invalid
+        let database = run_datalog_with_options(&program, facts, &options)
+            .map_err(|e| match e {
+                RunError::Invalid(message) => message,
+                e => panic!("{}", e),
+            })?
+            .database;
+        Ok(database.facts().map(|fact| fact.to_string()).collect())
+    }
+
+    #[test]
+    fn test_lattice_relations() {
+        // the direct edge to c is longer than the path through b, and the cycle back to a
+        // never gets shorter
+        let edges = facts(&[
+            &["start", "a", "0"],
+            &["edge", "a", "b", "1"],
+            &["edge", "b", "c", "2"],
+            &["edge", "a", "c", "5"],
+            &["edge", "c", "a", "1"],
+            &["edge", "c", "d", "1"],
+        ]);
+        for join_strategy in [JoinStrategy::Hash, JoinStrategy::Leapfrog] {
+            assert_eq!(
+                run(SHORTEST_PATHS, edges.clone(), join_strategy).unwrap(),
+                vec!["dist(a, 0)", "dist(b, 1)", "dist(c, 3)", "dist(d, 4)"]
+            );
+        }
+
+        // contains is exact, a value below the one of the key is not a fact
+        let program = parse_program(&mut Token::lexer(SHORTEST_PATHS)).unwrap();
+        let options = RunOptions {
+            externals: externals(),
+            all_relations: true,
+            ..RunOptions::default()
+        };
+        let database = run_datalog_with_options(&program, edges, &options)
+            .unwrap()
+            .database;
+        let dist = |d: &str| facts(&[&["dist", "c", d]]).pop().unwrap();
+        assert!(database.contains(&dist("3")));
+        assert!(!database.contains(&dist("5")));
+        assert_eq!(database.query("dist", [Some("c"), Some("3")]).count(), 1);
+
+        let constants = r#"
+            .decl assign(v, c) .input;
+            .decl copy(v, w) .input;
+            .decl val(v, c: lattice<ConstLattice>) .output;
+            .rule val(v, c) :- 1 assign(v, c);
+            .rule val(w, c) :- 2 val(v, c), copy(v, w);
+        "#;
+        let assignments = facts(&[
+            &["assign", "x", "1"],
+            &["assign", "y", "1"],
+            &["assign", "y", "2"],
+            &["copy", "x", "z"],
+            &["copy", "y", "z"],
+        ]);
+        assert_eq!(
+            run(constants, assignments, JoinStrategy::Auto).unwrap(),
+            vec!["val(x, 1)", "val(y, top)", "val(z, top)"]
+        );
+        assert_eq!(
+            ConstLattice::Top.join(&ConstLattice::bottom()),
+            ConstLattice::Top
+        );
+
+        // values that are not in the lattice, given or derived
+        let invalid: [&[&[&str]]; 2] = [
+            &[&["start", "a", "near"]],
+            &[&["start", "a", "0"], &["edge", "a", "b", "far"]],
+        ];
+        for edges in invalid {
+            assert!(run(SHORTEST_PATHS, facts(edges), JoinStrategy::Auto).is_err());
+        }
+        // values are parsed once, and one that did not pass the check is an error
+        let lattice = LatticeOps::new::<MinDistance>("MinDistance");
+        assert!(lattice.parse("far").is_err());
+        assert!(lattice.check("3").is_ok());
+        let unregistered = SHORTEST_PATHS.replace("MinDistance", "MaxDistance");
+        assert!(run(&unregistered, vec![], JoinStrategy::Auto).is_err());
+        // only the last parameter of a declaration has a lattice
+        let misplaced = SHORTEST_PATHS.replace(
+            "dist(x, d: lattice<MinDistance>)",
+            "dist(d: lattice<MinDistance>, x)",
+        );
+        assert!(parse_program(&mut Token::lexer(&misplaced)).is_err());
+        let in_rule = SHORTEST_PATHS.replace(".rule dist(x, d)", ".rule dist(x, d: lattice<L>)");
+        assert!(run(&in_rule, vec![], JoinStrategy::Auto).is_err());
+    }
+}
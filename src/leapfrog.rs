@@ -0,0 +1,242 @@
+use crate::database::Database;
+use crate::database::Row;
+use crate::implem::{partition_range, ParameterMapping, VariantResult};
+use crate::parse::{Declaration, Rule};
+use crate::plan::JoinStep;
+
+// Leapfrog triejoin, a worst-case optimal join of all body declarations at once.
+// The variables of the body are put in one global order, and the facts of every body
+// declaration are sorted by its variables in that order, which makes the sorted vector a
+// trie. The join binds one variable at a time: the declarations containing the variable
+// each have a sorted run of candidate values under the variables bound so far, and the
+// values in all of the runs are found by repeatedly seeking every run to the largest
+// current value (the leapfrog). Unlike joining pairwise, no intermediate result is larger
+// than the final one, which matters for cyclic bodies like triangles.
+
+// The facts of one body declaration as a trie
+struct Trie<'a> {
+    // the parameters of the facts, projected on the distinct variables of the declaration
+    // in the global order, sorted and deduplicated
+    tuples: Vec<Vec<&'a str>>,
+    // the tuples matching the variables bound so far
+    range: (usize, usize),
+}
+
+impl<'a> Trie<'a> {
+    fn new(rows: impl Iterator<Item = Row<'a>>, decl: &Declaration, vars: &[&str]) -> Trie<'a> {
+        // the first position of every variable of the declaration, in the global order
+        let positions = vars
+            .iter()
+            .filter_map(|var| decl.params.iter().position(|param| param == var))
+            .collect::<Vec<_>>();
+        let mut tuples = rows
+            .filter(|row| {
+                // a variable that appears twice has to have the same value both times
+                decl.params.iter().enumerate().all(|(i, param)| {
+                    let first = decl.params.iter().position(|p| p == param).unwrap();
+                    row.get(i) == row.get(first)
+                })
+            })
+            .map(|row| {
+                positions
+                    .iter()
+                    .map(|position| row.get(*position))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        tuples.sort();
+        tuples.dedup();
+        let range = (0, tuples.len());
+        Trie { tuples, range }
+    }
+}
+
+// Binds the variable at depth `binding.len()` to every value all of the tries at that
+// level agree on, and recurses to the next variable. `levels[depth]` has the tries
+// containing the variable at that depth and the column the variable is in.
+fn search<'a>(
+    tries: &mut [Trie<'a>],
+    levels: &[Vec<(usize, usize)>],
+    vars: &[&str],
+    binding: &mut Vec<&'a str>,
+    mapping: &mut ParameterMapping,
+) {
+    let depth = binding.len();
+    if depth == vars.len() {
+        mapping.parameter_maps.insert(
+            vars.iter()
+                .zip(binding.iter())
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+                .collect(),
+        );
+        return;
+    }
+    let level = &levels[depth];
+    let ranges = level
+        .iter()
+        .map(|(trie, _)| tries[*trie].range)
+        .collect::<Vec<_>>();
+    let mut positions = ranges.iter().map(|(start, _)| *start).collect::<Vec<_>>();
+    'leapfrog: loop {
+        let mut max = "";
+        for (i, (trie, column)) in level.iter().enumerate() {
+            if positions[i] == ranges[i].1 {
+                break 'leapfrog;
+            }
+            max = max.max(tries[*trie].tuples[positions[i]][*column]);
+        }
+        let mut agree = true;
+        for (i, (trie, column)) in level.iter().enumerate() {
+            let run = &tries[*trie].tuples[positions[i]..ranges[i].1];
+            positions[i] += run.partition_point(|tuple| tuple[*column] < max);
+            if positions[i] == ranges[i].1 {
+                break 'leapfrog;
+            }
+            agree &= tries[*trie].tuples[positions[i]][*column] == max;
+        }
+        if !agree {
+            continue;
+        }
+        for (i, (trie, column)) in level.iter().enumerate() {
+            let run = &tries[*trie].tuples[positions[i]..ranges[i].1];
+            let end = positions[i] + run.partition_point(|tuple| tuple[*column] <= max);
+            tries[*trie].range = (positions[i], end);
+            positions[i] = end;
+        }
+        binding.push(max);
+        search(tries, levels, vars, binding, mapping);
+        binding.pop();
+    }
+    for ((trie, _), range) in level.iter().zip(ranges) {
+        tries[*trie].range = range;
+    }
+}
+
+// The same as evaluate_variant, for a rule whose body has no externals: joins the body
+// declarations with leapfrog triejoin, the first declaration of `steps` reading the
+// chunk `partition` of the delta. The variables are bound in the order the hash join plan
+// binds them, so that the small delta comes first.
+pub(crate) fn leapfrog_variant(
+    rule: &Rule,
+    steps: &[JoinStep],
+    full: &Database,
+    delta: &Database,
+    partition: (usize, usize),
+) -> Option<VariantResult> {
+    let delta_idx = steps[0].body_idx;
+    let delta_table = delta.tables.get(&rule.body[delta_idx].name)?;
+
+    let mut vars: Vec<&str> = vec![];
+    for step in steps {
+        for param in &rule.body[step.body_idx].params {
+            if !vars.contains(&param.as_str()) {
+                vars.push(param);
+            }
+        }
+    }
+
+    let mut tries = vec![];
+    for step in steps {
+        let decl = &rule.body[step.body_idx];
+        let rows = if step.body_idx == delta_idx {
+            full.delta_rows(
+                delta_table,
+                partition_range(delta_table.len(), partition.0, partition.1),
+            )
+        } else if let Some(table) = full.tables.get(&decl.name) {
+            table.rows(0..table.len())
+        } else {
+            return Some(VariantResult {
+                mapping: None,
+                sizes: vec![],
+            });
+        };
+        tries.push(Trie::new(rows, decl, &vars));
+    }
+    let levels = vars
+        .iter()
+        .map(|var| {
+            steps
+                .iter()
+                .enumerate()
+                .filter_map(|(trie, step)| {
+                    let decl = &rule.body[step.body_idx];
+                    let mut decl_vars = vars
+                        .iter()
+                        .filter(|v| decl.params.iter().any(|param| param == *v));
+                    decl_vars
+                        .position(|v| v == var)
+                        .map(|column| (trie, column))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut mapping = ParameterMapping::new();
+    search(&mut tries, &levels, &vars, &mut vec![], &mut mapping);
+    mapping.parameter_keys = vars.iter().map(|var| var.to_string()).collect();
+    Some(VariantResult {
+        sizes: vec![mapping.parameter_maps.len()],
+        mapping: Some(mapping),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::implem::{run_datalog_with_options, RunOptions};
+    use crate::parse::{parse_program, Fact, Token};
+    use crate::plan::{plan_program, JoinStrategy};
+    use logos::Logos;
+
+    #[test]
+    fn test_leapfrog_matches_hash_join() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl tri(x, y, z) .output;
+            .decl path(x, z) .output;
+            .decl back(x, y) .output;
+            .rule tri(x, y, z) :- 3 edge(x, y), edge(y, z), edge(z, x);
+            .rule path(x, z) :- 2 edge(x, y), edge(y, z);
+            .rule back(x, y) :- 2 tri(x, y, z), edge(y, x);
+            "#,
+        ))
+        .unwrap();
+        let plan = plan_program(&program).unwrap();
+        let leapfrog = plan
+            .rule_plans()
+            .map(|rule_plan| (rule_plan.rule, rule_plan.leapfrog))
+            .collect::<Vec<_>>();
+        assert_eq!(leapfrog, vec![(0, true), (1, false), (2, false)]);
+        assert!(plan
+            .to_string()
+            .contains("leapfrog triejoin delta edge(x, y)"));
+
+        let n = 40;
+        let edges = (0..n)
+            .flat_map(|i| [(i, (i + 1) % n), (i, (i * 3) % n), (i, (i + 5) % n)])
+            .map(|(from, to)| Fact {
+                name: "edge".to_string(),
+                params: vec![format!("n{}", from), format!("n{}", to)],
+            })
+            .collect::<Vec<_>>();
+        let run = |join_strategy| {
+            let options = RunOptions {
+                join_strategy,
+                sorted: true,
+                ..RunOptions::default()
+            };
+            run_datalog_with_options(&program, edges.clone(), &options)
+                .unwrap()
+                .database
+                .facts()
+                .map(|fact| fact.to_string())
+                .collect::<Vec<_>>()
+        };
+        let hash = run(JoinStrategy::Hash);
+        assert!(hash.iter().any(|fact| fact.starts_with("tri(")));
+        assert!(hash.iter().any(|fact| fact.starts_with("back(")));
+        assert_eq!(hash, run(JoinStrategy::Auto));
+        assert_eq!(hash, run(JoinStrategy::Leapfrog));
+    }
+}
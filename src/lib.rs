@@ -1,9 +1,54 @@
+// Datalog interpreter with semi-naive evaluation.
+//
+// Engine is the entry point for embedding the interpreter: build it from a program, insert
+// typed tuples through relation handles, run, and read relations back as typed tuples.
+//
+//     let mut engine = Engine::builder().source(src).build()?;
+//     engine
+//         .relation::<(String, String)>("edge")
+//         .insert(("a".to_string(), "b".to_string()))?;
+//     engine.run()?;
+//     for (x, y) in engine.relation::<(String, String)>("reachable").iter()? {
+//         println!("{} -> {}", x, y);
+//     }
+//
+// run_datalog and run_datalog_with_options evaluate a program once on a Vec<Fact> and return
+// the .output relations as a Database.
+
 pub mod bril_rs_personal;
+pub mod codegen;
+pub mod constraint;
+pub mod database;
+pub mod engine;
+pub mod external;
 pub mod implem;
+pub mod lattice;
+pub mod leapfrog;
 pub mod optimize_bril;
+pub mod parallel;
 pub mod parse;
 pub mod parse_bril;
+pub mod plan;
+pub mod profile;
+pub mod progress;
+pub mod provenance;
+pub mod tuple;
+pub mod why_not;
 
-pub use implem::run_datalog;
+pub use constraint::Violation;
+pub use database::{Database, FactRef};
+pub use engine::{Engine, EngineBuilder, Relation};
+pub use external::{builtin_externals, ExternalKind, ExternalRelation, Externals};
+pub use implem::{
+    run_datalog, run_datalog_with_options, run_datalog_with_progress, Limit, RunError, RunOptions,
+    RunOutput,
+};
+pub use lattice::{ConstLattice, Lattice};
 pub use parse::{Fact, Program, Token};
 pub use parse_bril::parse_bril;
+pub use plan::{plan_program, JoinStrategy, Plan};
+pub use profile::Profile;
+pub use progress::{CancellationToken, Progress};
+pub use provenance::Provenance;
+pub use tuple::{Tuple, Value};
+pub use why_not::{why_not, why_not_with_externals, WhyNot};
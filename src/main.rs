@@ -1,16 +1,16 @@
-mod bril_rs_personal;
-mod implem;
-mod optimize_bril;
-mod parse;
-mod parse_bril;
-use implem::run_datalog;
+use datalogint::database::Database;
+use datalogint::external::builtin_externals;
+use datalogint::implem::{run_datalog_with_options, RunError, RunOptions};
+use datalogint::optimize_bril::perform_liveness_analysis;
+use datalogint::parse::{parse_fact, parse_fact_vector, parse_program, Fact, Program, Token};
+use datalogint::parse_bril::{bril_to_string, get_facts_from_bril_fn, parse_bril};
+use datalogint::plan::JoinStrategy;
+use datalogint::why_not::why_not_with_externals;
 use logos::Logos;
-use optimize_bril::perform_liveness_analysis;
-use parse::{parse_fact_vector, parse_program, DeclKind, Token};
-use parse_bril::{bril_to_string, get_facts_from_bril_fn, parse_bril};
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::hash::Hash;
+use std::time::Duration;
 
 // Baseline datalog interpreter for .dl files
 // .dl files are defined by the following grammar:
@@ -29,8 +29,38 @@ use std::hash::Hash;
 // <fact_2>
 // ...
 
+// Other commands:
+// run <program.dl> <facts.in> [--profile] [--profile-json <file>]
+//     [--max-iterations <n>] [--max-facts <n>] [--timeout <seconds>] [--unsorted]
+//     [--workers <n>] [--join <auto|hash|leapfrog>]
+//     prints the output facts sorted by relation and parameters (in storage order
+//     with --unsorted), --workers evaluates the rules on n threads, --join chooses between
+//     hash joins and leapfrog triejoin (by default for cyclic rule bodies), --profile prints
+//     per rule statistics to stderr
+//     and --profile-json writes them to <file>. When a limit is exceeded, the output
+//     facts derived so far are printed and the relations still growing are reported.
+//     When a constraint or assertion of the program does not hold, the output facts are
+//     printed and the violations reported.
+//     Relations can be declared with `lattice<ConstLattice>` on their last parameter.
+// run <program.dl> --plan
+//     prints the strata and join orders the program would be evaluated with
+// explain <program.dl> <facts.in> <fact>
+//     prints the tree of rules and facts that <fact> was derived from
+// why-not <program.dl> <facts.in> <fact>
+//     prints, for every rule that could derive <fact>, the body facts that are missing
+
 fn main() {
-    let filname = env::args().nth(1).expect("No filename provided");
+    let args = env::args().collect::<Vec<_>>();
+    let command = args.get(1).expect("No filename provided");
+    match command.as_str() {
+        "run" => run(&args[2..]),
+        "explain" => explain(&args[2..]),
+        "why-not" => explain_why_not(&args[2..]),
+        filname => optimize_bril_file(filname),
+    }
+}
+
+fn optimize_bril_file(filname: &str) {
     // println!("{}", filname);
     let src = std::fs::read_to_string(filname).expect("Error reading file");
 
@@ -39,3 +69,172 @@ fn main() {
 
     println!("{}", bril_to_string(&prog));
 }
+
+fn exit_with_error(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn read_program_and_facts(program_file: &str, facts_file: &str) -> (Program, Vec<Fact>) {
+    let program_src = std::fs::read_to_string(program_file).expect("Error reading file");
+    let program = parse_program(&mut Token::lexer(&program_src))
+        .unwrap_or_else(|e| exit_with_error(format!("Error parsing rules: {}", e)));
+    let facts_src = std::fs::read_to_string(facts_file).expect("Error reading file");
+    let facts = parse_fact_vector(&mut Token::lexer(&facts_src))
+        .unwrap_or_else(|e| exit_with_error(format!("Error parsing facts: {}", e)));
+    (program, facts)
+}
+
+// The lattices programs run from the command line can declare relations with
+fn run(args: &[String]) {
+    let mut options = RunOptions {
+        sorted: true,
+        externals: builtin_externals(),
+        ..RunOptions::default()
+    };
+    let mut print_profile = false;
+    let mut print_plan = false;
+    let mut profile_json = None;
+    let mut files = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => {
+                options.profile = true;
+                print_profile = true;
+            }
+            "--plan" => print_plan = true,
+            "--unsorted" => options.sorted = false,
+            "--profile-json" => {
+                options.profile = true;
+                profile_json = Some(args.next().unwrap_or_else(|| {
+                    exit_with_error("--profile-json expects a file name".to_string())
+                }));
+            }
+            "--max-iterations" => options.max_iterations = Some(parse_limit(arg, args.next())),
+            "--max-facts" => options.max_facts = Some(parse_limit(arg, args.next())),
+            "--workers" => options.workers = parse_limit(arg, args.next()),
+            "--join" => {
+                options.join_strategy = match args.next().map(|value| value.as_str()) {
+                    Some("auto") => JoinStrategy::Auto,
+                    Some("hash") => JoinStrategy::Hash,
+                    Some("leapfrog") => JoinStrategy::Leapfrog,
+                    _ => exit_with_error("--join expects auto, hash or leapfrog".to_string()),
+                }
+            }
+            "--timeout" => {
+                options.timeout = Some(Duration::from_secs(parse_limit(arg, args.next()) as u64))
+            }
+            _ => files.push(arg),
+        }
+    }
+    if print_plan {
+        let [program_file] = files[..] else {
+            exit_with_error("Usage: run <program.dl> --plan".to_string());
+        };
+        let program_src = std::fs::read_to_string(program_file).expect("Error reading file");
+        let program = parse_program(&mut Token::lexer(&program_src))
+            .unwrap_or_else(|e| exit_with_error(format!("Error parsing rules: {}", e)));
+        let plan = datalogint::plan::plan_program_with_externals(&program, &options.externals)
+            .unwrap_or_else(|e| exit_with_error(format!("Error planning program: {}", e)));
+        print!("{}", plan.with_join_strategy(options.join_strategy));
+        return;
+    }
+    let [program_file, facts_file] = files[..] else {
+        exit_with_error(
+            "Usage: run <program.dl> <facts.in> [--profile] [--profile-json <file>]".to_string(),
+        );
+    };
+    let (program, facts) = read_program_and_facts(program_file, facts_file);
+
+    let print_output_facts = |database: Database| {
+        for fact in database.facts() {
+            println!("{};", fact);
+        }
+    };
+
+    let output = match run_datalog_with_options(&program, facts, &options) {
+        Ok(output) => output,
+        Err(RunError::LimitExceeded {
+            limit,
+            partial,
+            growing,
+        }) => {
+            print_output_facts(partial);
+            exit_with_error(format!(
+                "Evaluation stopped: {} exceeded, still growing: {}",
+                limit,
+                growing.join(", ")
+            ));
+        }
+        Err(RunError::ConstraintViolated {
+            violations,
+            database,
+        }) => {
+            print_output_facts(database);
+            let violations = violations
+                .iter()
+                .map(|violation| format!("  {}", violation))
+                .collect::<Vec<_>>();
+            exit_with_error(format!("Constraints violated:\n{}", violations.join("\n")));
+        }
+        Err(e) => exit_with_error(format!("Error running analysis: {}", e)),
+    };
+    print_output_facts(output.database);
+
+    if let Some(profile) = output.profile {
+        if print_profile {
+            eprint!("{}", profile.report());
+        }
+        if let Some(path) = profile_json {
+            std::fs::write(path, profile.to_json()).expect("Error writing file");
+        }
+    }
+}
+
+fn parse_limit(flag: &str, value: Option<&String>) -> usize {
+    value
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| exit_with_error(format!("{} expects a number", flag)))
+}
+
+fn explain(args: &[String]) {
+    let [program_file, facts_file, fact] = args else {
+        exit_with_error("Usage: explain <program.dl> <facts.in> <fact>".to_string());
+    };
+    let (program, facts) = read_program_and_facts(program_file, facts_file);
+    let fact = parse_fact(&mut Token::lexer(fact))
+        .unwrap_or_else(|e| exit_with_error(format!("Error parsing fact: {}", e)));
+
+    let options = RunOptions {
+        provenance: true,
+        externals: builtin_externals(),
+        ..RunOptions::default()
+    };
+    let output = run_datalog_with_options(&program, facts, &options)
+        .unwrap_or_else(|e| exit_with_error(format!("Error running analysis: {}", e)));
+    match output.provenance.unwrap().explain(&fact) {
+        Some(tree) => print!("{}", tree),
+        None => println!("{} is not derived", fact),
+    }
+}
+
+fn explain_why_not(args: &[String]) {
+    let [program_file, facts_file, fact] = args else {
+        exit_with_error("Usage: why-not <program.dl> <facts.in> <fact>".to_string());
+    };
+    let (program, facts) = read_program_and_facts(program_file, facts_file);
+    let fact = parse_fact(&mut Token::lexer(fact))
+        .unwrap_or_else(|e| exit_with_error(format!("Error parsing fact: {}", e)));
+
+    let options = RunOptions {
+        all_relations: true,
+        externals: builtin_externals(),
+        ..RunOptions::default()
+    };
+    let output = run_datalog_with_options(&program, facts, &options)
+        .unwrap_or_else(|e| exit_with_error(format!("Error running analysis: {}", e)));
+    let explanation = why_not_with_externals(&program, &options.externals, &output.database, &fact)
+        .unwrap_or_else(|e| exit_with_error(format!("Error explaining fact: {}", e)));
+    print!("{}", explanation);
+}
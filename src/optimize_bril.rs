@@ -47,10 +47,7 @@ pub fn perform_liveness_analysis(mut bril_program: BBProgram) -> BBProgram {
         let facts = get_facts_from_bril_fn(func);
         let output_facts = run_datalog(&datalog_program, facts).unwrap();
 
-        let facts_out = output_facts
-            .iter()
-            .filter(|f| f.name == "var_live")
-            .collect::<Vec<_>>();
+        let facts_out = output_facts.relation("var_live");
 
         let mut live_by_line: HashMap<String, HashSet<String>> = HashMap::new();
 
@@ -58,9 +55,9 @@ pub fn perform_liveness_analysis(mut bril_program: BBProgram) -> BBProgram {
 
         for fact in facts_out {
             live_by_line
-                .entry(fact.params[0].clone())
+                .entry(fact.param(0).to_string())
                 .or_insert(HashSet::new())
-                .insert(fact.params[1].clone());
+                .insert(fact.param(1).to_string());
         }
 
         for block in &mut func.blocks {
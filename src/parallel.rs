@@ -0,0 +1,202 @@
+use crate::database::Database;
+use crate::implem::{evaluate_variant, Limits, ParameterMapping, VariantResult};
+use crate::leapfrog::leapfrog_variant;
+use crate::parse::Program;
+use crate::plan::{Plan, RulePlan};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+// Multi-threaded evaluation of one iteration of a stratum. The rules of an iteration only
+// read the database, so every join order of every rule is an independent unit of work.
+// Join orders whose delta relation is large are split further into chunks of the delta.
+// Workers take units from a shared counter, and the results are put back in unit order,
+// which is the order of a single threaded evaluation.
+
+// by default, a join order is only split into chunks from this many delta facts on
+const MIN_PARTITION_FACTS: usize = 4096;
+
+// The parameter mappings of one rule, as returned by get_rule_parameter_mappings
+pub(crate) struct RuleEvaluation {
+    pub(crate) mappings: Vec<ParameterMapping>,
+    pub(crate) join_sizes: Vec<Vec<usize>>,
+    // summed over the threads that evaluated the rule
+    pub(crate) time_ns: u64,
+}
+
+// Evaluates `rule_plans` on `limits.workers` scoped threads, returning one evaluation per
+// rule plan
+pub(crate) fn evaluate_rules_parallel(
+    program: &Program,
+    plan: &Plan,
+    rule_plans: &[RulePlan],
+    full: &Database,
+    delta: &Database,
+    external_delta: bool,
+    limits: &Limits,
+) -> Vec<RuleEvaluation> {
+    let workers = limits.workers;
+    let partition_facts = limits.partition_facts.unwrap_or(MIN_PARTITION_FACTS);
+    // (rule plan, join order, (chunk, number of chunks))
+    let mut units = vec![];
+    for (rule_pos, rule_plan) in rule_plans.iter().enumerate() {
+        let rule = &program.rules[rule_plan.rule];
+        for (variant_idx, steps) in rule_plan.variants.iter().enumerate() {
+            let delta_size = delta
+                .tables
+                .get(&rule.body[steps[0].body_idx].name)
+                .map_or(0, |table| table.len());
+            let num_partitions = if delta_size >= partition_facts {
+                workers
+            } else {
+                1
+            };
+            for partition_idx in 0..num_partitions {
+                units.push((rule_pos, variant_idx, (partition_idx, num_partitions)));
+            }
+        }
+    }
+
+    let next_unit = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let handles = (0..workers.min(units.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let unit_idx = next_unit.fetch_add(1, Ordering::Relaxed);
+                        let Some(&(rule_pos, variant_idx, partition)) = units.get(unit_idx) else {
+                            break;
+                        };
+                        let start = Instant::now();
+                        let rule_plan = &rule_plans[rule_pos];
+                        let rule = &program.rules[rule_plan.rule];
+                        let steps = &rule_plan.variants[variant_idx];
+                        let result = if rule_plan.leapfrog {
+                            leapfrog_variant(rule, steps, full, delta, partition)
+                        } else {
+                            evaluate_variant(
+                                &rule.body,
+                                steps,
+                                &plan.externals,
+                                full,
+                                delta,
+                                external_delta,
+                                partition,
+                            )
+                        };
+                        done.push((unit_idx, result, start.elapsed().as_nanos() as u64));
+                    }
+                    done
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(unit_idx, _, _)| *unit_idx);
+
+    let mut evaluations = rule_plans
+        .iter()
+        .map(|_| RuleEvaluation {
+            mappings: vec![],
+            join_sizes: vec![],
+            time_ns: 0,
+        })
+        .collect::<Vec<_>>();
+    for (unit_idx, result, time_ns) in results {
+        let (rule_pos, _, (partition_idx, _)) = units[unit_idx];
+        let evaluation = &mut evaluations[rule_pos];
+        evaluation.time_ns += time_ns;
+        let Some(VariantResult { mapping, sizes }) = result else {
+            continue;
+        };
+        // the chunks of a join order add up to the sizes of the whole join
+        match evaluation.join_sizes.last_mut() {
+            Some(total) if partition_idx > 0 => {
+                for (i, size) in sizes.into_iter().enumerate() {
+                    match total.get_mut(i) {
+                        Some(total_size) => *total_size += size,
+                        None => total.push(size),
+                    }
+                }
+            }
+            _ => evaluation.join_sizes.push(sizes),
+        }
+        evaluation.mappings.extend(mapping);
+    }
+    evaluations
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::implem::{run_datalog_with_options, RunOptions, RunOutput};
+    use crate::parse::{parse_program, Fact, Token};
+    use logos::Logos;
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl link(x, y) .input;
+            .decl two_hops(x, z) .output;
+            .decl chain(x, y) .output;
+            .rule two_hops(x, z) :- 2 edge(x, y), edge(y, z);
+            .rule chain(x, y) :- 1 link(x, y);
+            .rule chain(x, z) :- 2 chain(x, y), link(y, z);
+            "#,
+        ))
+        .unwrap();
+        // enough edges for the joins of the first iteration to be split into chunks, and
+        // a chain of links that takes a few iterations
+        let n = 6000;
+        let fact = |name: &str, from: usize, to: usize| Fact {
+            name: name.to_string(),
+            params: vec![format!("n{}", from), format!("n{}", to)],
+        };
+        let facts = (0..n)
+            .map(|i| fact("edge", i, (i * 7 + 3) % n))
+            .chain((0..30).map(|i| fact("link", i, i + 1)))
+            .collect::<Vec<_>>();
+
+        let run = |workers| {
+            let options = RunOptions {
+                workers,
+                sorted: true,
+                profile: true,
+                ..RunOptions::default()
+            };
+            run_datalog_with_options(&program, facts.clone(), &options).unwrap()
+        };
+        let output_facts = |output: &RunOutput| {
+            output
+                .database
+                .facts()
+                .map(|fact| fact.to_string())
+                .collect::<Vec<_>>()
+        };
+        let join_sizes = |output: &RunOutput| {
+            output
+                .profile
+                .as_ref()
+                .unwrap()
+                .iterations
+                .iter()
+                .flat_map(|iteration| iteration.rules.iter())
+                .map(|rule| (rule.rule, rule.join_sizes.clone(), rule.new_facts))
+                .collect::<Vec<_>>()
+        };
+        let sequential = run(1);
+        let parallel = run(4);
+        assert_eq!(sequential.database.relation("two_hops").count(), n);
+        assert_eq!(output_facts(&sequential), output_facts(&parallel));
+        assert_eq!(join_sizes(&sequential), join_sizes(&parallel));
+    }
+}
@@ -1,5 +1,3 @@
-use either::Either;
-
 use logos::{Lexer, Logos};
 use std::fmt;
 use std::result::Result;
@@ -20,7 +18,14 @@ pub enum Token {
     #[token(".output")]
     Output,
 
-    #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_owned())]
+    #[token(".eqrel")]
+    EqRel,
+
+    #[token(".assert")]
+    AssertHeader,
+
+    // a leading @ names an external predicate or functor
+    #[regex(r"@?[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_owned())]
     Ident(String),
 
     #[token(",")]
@@ -44,7 +49,20 @@ pub enum Token {
     #[token(":-")]
     Implies,
 
-    #[regex(r"[0-9]+", |lex| lex.slice().to_owned().parse::<u32>().unwrap())]
+    #[token("=")]
+    Equals,
+
+    #[token(":")]
+    Colon,
+
+    #[token("<")]
+    AngleOpen,
+
+    #[token(">")]
+    AngleClose,
+
+    // a number too large for a u32 is an error token
+    #[regex(r"[0-9]+", |lex| lex.slice().parse::<u32>().ok())]
     Number(u32),
 }
 
@@ -64,7 +82,17 @@ pub struct Declaration {
     pub name: String,
     pub params: Vec<String>,
     pub kind: DeclKind,
+    // set by .eqrel: a binary relation closed under reflexivity, symmetry and transitivity
+    pub eqrel: bool,
+    // set by `v: lattice<L>` on the last parameter: the lattice its values are joined in
+    pub lattice: Option<String>,
 }
+impl fmt::Display for Declaration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", self.name, self.params.join(", "))
+    }
+}
+
 impl FactLike for Declaration {
     fn name(&self) -> &str {
         &self.name
@@ -74,37 +102,57 @@ impl FactLike for Declaration {
     }
 }
 
-fn parse_params(lexer: &mut Lexer<'_, Token>) -> Result<Vec<String>, String> {
+// `: lattice<L>` after a parameter, returns L. Like parse_assertion, stops at the first
+// token that does not fit.
+fn parse_lattice(lexer: &mut Lexer<'_, Token>) -> Result<String, String> {
+    let error = || "Expected lattice<...> after :".to_string();
+    if next_ident(lexer).as_deref() != Some("lattice")
+        || !matches!(lexer.next(), Some(Ok(Token::AngleOpen)))
+    {
+        return Err(error());
+    }
+    let lattice = next_ident(lexer).ok_or_else(error)?;
+    if !matches!(lexer.next(), Some(Ok(Token::AngleClose))) {
+        return Err(error());
+    }
+    Ok(lattice)
+}
+
+// The parameters up to the closing parenthesis, and the lattice of the last one if it has one
+fn parse_params(lexer: &mut Lexer<'_, Token>) -> Result<(Vec<String>, Option<String>), String> {
     let mut params = vec![];
+    let mut lattice = None;
     while let Some(token) = lexer.next() {
         match token {
-            Ok(Token::Ident(ident)) => {
+            Ok(Token::Ident(ident)) if lattice.is_none() => {
                 params.push(ident);
             }
-            Ok(Token::Comma) => {
+            Ok(Token::Colon) if !params.is_empty() && lattice.is_none() => {
+                lattice = Some(parse_lattice(lexer)?);
+            }
+            Ok(Token::Comma) if lattice.is_none() => {
                 continue;
             }
             Ok(Token::ParenClose) => {
-                break;
+                return Ok((params, lattice));
             }
             _ => {
                 return Err(format!("{:?} is not a valid token in parse_params", token));
             }
         }
     }
-    Ok(params)
+    Err("Unexpected end of input in parse_params".to_string())
 }
 
-// Parse a declaration with or without an the input flag.
-fn parse_declaration_or_fact(
-    lexer: &mut Lexer<'_, Token>,
-    with_input: bool,
-    is_declaration: bool,
-) -> Result<Either<Declaration, Fact>, String> {
+// Parse a declaration with or without an the input flag. Facts are parsed the same way,
+// without the flag. The name is empty if the input ends before it.
+fn parse_atom(lexer: &mut Lexer<'_, Token>, with_input: bool) -> Result<Declaration, String> {
     // .decl <ident>(<params>) .<input/output>
     let mut name = String::new();
     let mut params = vec![];
     let mut kind = DeclKind::Input;
+    let mut eqrel = false;
+    let mut lattice = None;
 
     while let Some(token) = lexer.next() {
         match token {
@@ -112,7 +160,7 @@ fn parse_declaration_or_fact(
                 name = ident;
             }
             Ok(Token::ParenOpen) => {
-                params = parse_params(lexer)?;
+                (params, lattice) = parse_params(lexer)?;
             }
             Ok(Token::Input) => {
                 kind = DeclKind::Input;
@@ -120,6 +168,9 @@ fn parse_declaration_or_fact(
             Ok(Token::Output) => {
                 kind = DeclKind::Output;
             }
+            Ok(Token::EqRel) => {
+                eqrel = true;
+            }
             Ok(Token::Semicolon) => {
                 break;
             }
@@ -147,18 +198,32 @@ fn parse_declaration_or_fact(
         }
     }
 
-    if is_declaration {
-        Ok(Either::Left(Declaration {
-            name: name,
-            params: params,
-            kind: kind,
-        }))
-    } else {
-        Ok(Either::Right(Fact {
-            name: name,
-            params: params,
-        }))
+    Ok(Declaration {
+        name,
+        params,
+        kind,
+        eqrel,
+        lattice,
+    })
+}
+
+fn parse_declaration(
+    lexer: &mut Lexer<'_, Token>,
+    with_input: bool,
+) -> Result<Declaration, String> {
+    let decl = parse_atom(lexer, with_input)?;
+    if decl.name.is_empty() {
+        return Err("Expected a declaration".to_string());
+    }
+    Ok(decl)
+}
+
+fn parse_fact_params(lexer: &mut Lexer<'_, Token>) -> Result<Fact, String> {
+    let Declaration { name, params, .. } = parse_atom(lexer, false)?;
+    if name.is_empty() {
+        return Err("Expected a fact".to_string());
     }
+    Ok(Fact { name, params })
 }
 
 #[derive(Debug)]
@@ -167,10 +232,49 @@ pub struct Rule {
     pub body: Vec<Declaration>,
 }
 
-fn parse_rule(lexer: &mut Lexer<'_, Token>) -> Result<Rule, String> {
-    let head = parse_declaration_or_fact(lexer, false, true)?
-        .left()
-        .unwrap();
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body = self
+            .body
+            .iter()
+            .map(|decl| decl.to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{} :- {}", self.head, body.join(", "))
+    }
+}
+
+// A rule without a head, `:- <num_decl> <body>;`, whose body must never hold
+#[derive(Debug)]
+pub struct Constraint {
+    pub body: Vec<Declaration>,
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body = self
+            .body
+            .iter()
+            .map(|decl| decl.to_string())
+            .collect::<Vec<_>>();
+        write!(f, ":- {}", body.join(", "))
+    }
+}
+
+// `.assert count(<relation>) = <count>;`, checked once the program is evaluated
+#[derive(Debug)]
+pub struct Assertion {
+    pub relation: String,
+    pub count: usize,
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "count({}) = {}", self.relation, self.count)
+    }
+}
+
+// <num_decl> <decl>, <decl>, ...
+fn parse_body(lexer: &mut Lexer<'_, Token>) -> Result<Vec<Declaration>, String> {
     let mut body = vec![];
 
     let num_decl = match lexer.next() {
@@ -181,44 +285,93 @@ fn parse_rule(lexer: &mut Lexer<'_, Token>) -> Result<Rule, String> {
     };
 
     for _ in 0..num_decl {
-        body.push(
-            parse_declaration_or_fact(lexer, false, true)?
-                .left()
-                .unwrap(),
-        );
+        body.push(parse_declaration(lexer, false)?);
     }
 
+    Ok(body)
+}
+
+fn parse_rule(lexer: &mut Lexer<'_, Token>) -> Result<Rule, String> {
+    let head = parse_declaration(lexer, false)?;
+    let body = parse_body(lexer)?;
     Ok(Rule { head, body })
 }
 
+fn next_ident(lexer: &mut Lexer<'_, Token>) -> Option<String> {
+    match lexer.next() {
+        Some(Ok(Token::Ident(ident))) => Some(ident),
+        _ => None,
+    }
+}
+
+// `count(<relation>) = <number>;` after .assert. Every token is checked before the next one
+// is read, so that the lexer is at the offending token on an error.
+fn parse_assertion(lexer: &mut Lexer<'_, Token>) -> Result<Assertion, String> {
+    let error = || "Expected count(<relation>) after .assert".to_string();
+    if next_ident(lexer).as_deref() != Some("count")
+        || !matches!(lexer.next(), Some(Ok(Token::ParenOpen)))
+    {
+        return Err(error());
+    }
+    let relation = next_ident(lexer).ok_or_else(error)?;
+    if !matches!(lexer.next(), Some(Ok(Token::ParenClose))) {
+        return Err(error());
+    }
+    let error = || format!("Expected = <number>; after .assert count({})", relation);
+    if !matches!(lexer.next(), Some(Ok(Token::Equals))) {
+        return Err(error());
+    }
+    let count = match lexer.next() {
+        Some(Ok(Token::Number(count))) => count as usize,
+        _ => return Err(error()),
+    };
+    if !matches!(lexer.next(), Some(Ok(Token::Semicolon))) {
+        return Err(error());
+    }
+    Ok(Assertion { relation, count })
+}
+
 #[derive(Debug)]
 pub struct Program {
     pub decls: Vec<Declaration>,
     pub rules: Vec<Rule>,
+    pub constraints: Vec<Constraint>,
+    pub assertions: Vec<Assertion>,
 }
 pub fn parse_program(lexer: &mut Lexer<'_, Token>) -> Result<Program, String> {
     let mut decls = vec![];
     let mut rules = vec![];
+    let mut constraints = vec![];
+    let mut assertions = vec![];
 
     while let Some(token) = lexer.next() {
         match token {
             Ok(Token::DeclHeader) => {
-                decls.push(
-                    parse_declaration_or_fact(lexer, true, true)?
-                        .left()
-                        .unwrap(),
-                );
+                decls.push(parse_declaration(lexer, true)?);
             }
             Ok(Token::RuleHeader) => {
                 rules.push(parse_rule(lexer)?);
             }
+            Ok(Token::Implies) => {
+                constraints.push(Constraint {
+                    body: parse_body(lexer)?,
+                });
+            }
+            Ok(Token::AssertHeader) => {
+                assertions.push(parse_assertion(lexer)?);
+            }
             _ => {
                 return Err(format!("{:?} is not a valid token", token));
             }
         }
     }
 
-    Ok(Program { decls, rules })
+    Ok(Program {
+        decls,
+        rules,
+        constraints,
+        assertions,
+    })
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -242,6 +395,11 @@ impl FactLike for Fact {
     }
 }
 
+// Parse a single fact such as `edge(a, b)`, the trailing semicolon is optional
+pub fn parse_fact(lexer: &mut Lexer<'_, Token>) -> Result<Fact, String> {
+    parse_fact_params(lexer)
+}
+
 pub fn parse_fact_vector(lexer: &mut Lexer<'_, Token>) -> Result<Vec<Fact>, String> {
     let mut facts = vec![];
 
@@ -253,12 +411,42 @@ pub fn parse_fact_vector(lexer: &mut Lexer<'_, Token>) -> Result<Vec<Fact>, Stri
     };
 
     for _ in 0..num_facts {
-        facts.push(
-            parse_declaration_or_fact(lexer, false, false)?
-                .right()
-                .unwrap(),
-        );
+        facts.push(parse_fact_params(lexer)?);
     }
 
     Ok(facts)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_fact_vector, parse_program, Token};
+    use logos::Logos;
+
+    #[test]
+    fn test_malformed_input() {
+        let programs = [
+            ".rule path(x, z) :- 99999999999 edge(x, y);",
+            ".rule path(x, z) :- 4294967295 edge(x, y);",
+            ".rule path(x, z) :- 2 edge(x, y)",
+            ".decl edge(x, y",
+            ".decl",
+            ".rule",
+        ];
+        for src in programs {
+            assert!(parse_program(&mut Token::lexer(src)).is_err(), "{}", src);
+        }
+        for src in [
+            "99999999999 edge(a, b);",
+            "3 edge(a, b); edge(b, c);",
+            "1 edge(a",
+        ] {
+            assert!(
+                parse_fact_vector(&mut Token::lexer(src)).is_err(),
+                "{}",
+                src
+            );
+        }
+        let facts = parse_fact_vector(&mut Token::lexer("2 edge(a, b); edge(b, c)")).unwrap();
+        assert_eq!(facts.len(), 2);
+    }
+}
@@ -0,0 +1,732 @@
+use crate::external::{is_external, ExternalKind, Externals};
+use crate::parse::{Declaration, Program};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+// One join in the evaluation of a rule: the body declaration is hash joined with the
+// parameter mappings built so far on `keys`, the parameters they have in common.
+// For an external predicate or functor, `keys` are its arguments.
+#[derive(Debug, Clone)]
+pub struct JoinStep {
+    pub body_idx: usize,
+    // read from the facts that are new since the last round instead of the full relation
+    pub delta: bool,
+    pub keys: Vec<String>,
+    // no parameters in common with the earlier steps, so the join is a cross product
+    pub cross_product: bool,
+}
+
+// Semi-naive evaluation of a rule runs one join order per body declaration, with that
+// declaration reading from the delta
+#[derive(Debug, Clone)]
+pub struct RulePlan {
+    pub rule: usize,
+    pub variants: Vec<Vec<JoinStep>>,
+    // the body declarations share variables in a cycle, like edge(x, y), edge(y, z), edge(z, x)
+    pub cyclic: bool,
+    // join the whole body at once with leapfrog triejoin instead of pairwise hash joins,
+    // with the variables in the order the join order binds them
+    pub leapfrog: bool,
+}
+
+// How the body declarations of rules are joined
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum JoinStrategy {
+    // leapfrog triejoin for cyclic bodies, hash joins otherwise
+    #[default]
+    Auto,
+    Hash,
+    Leapfrog,
+}
+
+// A strongly connected component of the relation dependency graph, together with the
+// rules that derive its relations. Strata are evaluated to a fixpoint one after another.
+#[derive(Debug, Clone)]
+pub struct Stratum {
+    pub relations: Vec<String>,
+    pub recursive: bool,
+    pub rules: Vec<RulePlan>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Plan {
+    // in evaluation order, a stratum only depends on itself and earlier strata
+    pub strata: Vec<Stratum>,
+    // the join order of every constraint, checked on the full database after evaluation
+    pub constraints: Vec<Vec<JoinStep>>,
+    rule_text: Vec<String>,
+    body_text: Vec<Vec<String>>,
+    constraint_text: Vec<String>,
+    constraint_body_text: Vec<Vec<String>>,
+    // per rule, whether its body has no externals, which leapfrog triejoin can't call
+    leapfrog_supported: Vec<bool>,
+    pub(crate) externals: Externals,
+}
+
+impl Plan {
+    pub fn rule_plans(&self) -> impl Iterator<Item = &RulePlan> {
+        self.strata.iter().flat_map(|stratum| stratum.rules.iter())
+    }
+
+    // Chooses the join of every rule. Rules calling externals are always hash joined.
+    pub fn with_join_strategy(mut self, strategy: JoinStrategy) -> Plan {
+        for stratum in &mut self.strata {
+            for rule_plan in &mut stratum.rules {
+                rule_plan.leapfrog = self.leapfrog_supported[rule_plan.rule]
+                    && match strategy {
+                        JoinStrategy::Auto => rule_plan.cyclic,
+                        JoinStrategy::Hash => false,
+                        JoinStrategy::Leapfrog => true,
+                    };
+            }
+        }
+        self
+    }
+}
+
+// `place` names where the declaration is used in errors, e.g. "rule 2" or "constraint 0"
+fn verify_declaration(
+    decl: &Declaration,
+    arities: &HashMap<&str, usize>,
+    place: &str,
+) -> Result<(), String> {
+    let Some(arity) = arities.get(decl.name.as_str()) else {
+        return Err(format!(
+            "Relation {} used in {} is not declared",
+            decl.name, place
+        ));
+    };
+    if decl.params.len() != *arity {
+        return Err(format!(
+            "Relation {} has the wrong number of parameters in {}",
+            decl.name, place
+        ));
+    }
+    if decl.lattice.is_some() {
+        return Err(format!(
+            "Lattice of {} given in {}, only declarations have one",
+            decl.name, place
+        ));
+    }
+    Ok(())
+}
+
+fn verify_external(decl: &Declaration, externals: &Externals, place: &str) -> Result<(), String> {
+    let Some(external) = externals.get(&decl.name) else {
+        return Err(format!(
+            "External {} used in {} is not registered",
+            decl.name, place
+        ));
+    };
+    if decl.params.len() != external.num_params() {
+        return Err(format!(
+            "External {} has {} parameters in {}, expected {}",
+            decl.name,
+            decl.params.len(),
+            place,
+            external.num_params()
+        ));
+    }
+    Ok(())
+}
+
+fn verify_body(
+    body: &[Declaration],
+    arities: &HashMap<&str, usize>,
+    externals: &Externals,
+    place: &str,
+) -> Result<(), String> {
+    for decl in body {
+        if is_external(&decl.name) {
+            if decl.lattice.is_some() {
+                return Err(format!("External {} cannot have a lattice", decl.name));
+            }
+            verify_external(decl, externals, place)?;
+        } else {
+            verify_declaration(decl, arities, place)?;
+        }
+    }
+    if body.iter().all(|decl| is_external(&decl.name)) {
+        return Err(format!(
+            "The body of {} needs a relation besides externals",
+            place
+        ));
+    }
+    Ok(())
+}
+
+//  Verify that the rules are valid according to the declarations
+//  Relations in rules are declared and have the correct number of parameters
+//  No relation is declared with a name starting with @
+//  External relations have the arity of their declaration
+//  External predicates and functors are registered and have the correct number of parameters
+//  Every parameter of the head is bound by the body
+//  Lattices are registered, and only given in declarations
+//  Constraints are checked like rule bodies, assertions count declared relations
+fn verify_rules(program: &Program, externals: &Externals) -> Result<(), String> {
+    let arities = program
+        .decls
+        .iter()
+        .map(|decl| (decl.name.as_str(), decl.params.len()))
+        .collect::<HashMap<_, _>>();
+    if let Some(decl) = program.decls.iter().find(|decl| is_external(&decl.name)) {
+        return Err(format!(
+            "Relation {} cannot be declared, names starting with @ are externals",
+            decl.name
+        ));
+    }
+    for decl in &program.decls {
+        if let Some(relation) = externals.get_relation(&decl.name) {
+            if decl.lattice.is_some() {
+                return Err(format!(
+                    "External relation {} cannot have a lattice",
+                    decl.name
+                ));
+            }
+            if relation.arity() != decl.params.len() {
+                return Err(format!(
+                    "External relation {} has {} parameters, but is declared with {}",
+                    decl.name,
+                    relation.arity(),
+                    decl.params.len()
+                ));
+            }
+        }
+        if decl.eqrel && decl.params.len() != 2 {
+            return Err(format!(
+                "Equivalence relation {} has {} parameters instead of 2",
+                decl.name,
+                decl.params.len()
+            ));
+        }
+        if let Some(lattice) = &decl.lattice {
+            if externals.get_lattice(lattice).is_none() {
+                return Err(format!(
+                    "Lattice {} of relation {} is not registered",
+                    lattice, decl.name
+                ));
+            }
+            if decl.eqrel {
+                return Err(format!(
+                    "Relation {} cannot be both an equivalence relation and have a lattice",
+                    decl.name
+                ));
+            }
+        }
+    }
+
+    for (rule_idx, rule) in program.rules.iter().enumerate() {
+        if is_external(&rule.head.name) {
+            return Err(format!(
+                "External {} cannot be the head of rule {}",
+                rule.head.name, rule_idx
+            ));
+        }
+        let place = format!("rule {}", rule_idx);
+        verify_declaration(&rule.head, &arities, &place)?;
+        verify_body(&rule.body, &arities, externals, &place)?;
+        let bound = rule
+            .body
+            .iter()
+            .flat_map(|decl| decl.params.iter())
+            .collect::<HashSet<_>>();
+        if let Some(param) = rule.head.params.iter().find(|p| !bound.contains(p)) {
+            return Err(format!(
+                "Parameter {} in the head of rule {} does not appear in its body",
+                param, rule_idx
+            ));
+        }
+    }
+    for (constraint_idx, constraint) in program.constraints.iter().enumerate() {
+        let place = format!("constraint {}", constraint_idx);
+        verify_body(&constraint.body, &arities, externals, &place)?;
+    }
+    if let Some(assertion) = program
+        .assertions
+        .iter()
+        .find(|assertion| !arities.contains_key(assertion.relation.as_str()))
+    {
+        return Err(format!(
+            "Relation {} used in .assert is not declared",
+            assertion.relation
+        ));
+    }
+    Ok(())
+}
+
+// Tarjan's algorithm. Components are found in reverse topological order, so every
+// component comes after the components it depends on.
+struct SccFinder<'a> {
+    edges: &'a HashMap<usize, BTreeSet<usize>>,
+    index: Vec<Option<usize>>,
+    lowlink: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    next_index: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl SccFinder<'_> {
+    fn visit(&mut self, node: usize) {
+        self.index[node] = Some(self.next_index);
+        self.lowlink[node] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+
+        for &next in self.edges.get(&node).into_iter().flatten() {
+            match self.index[next] {
+                None => {
+                    self.visit(next);
+                    self.lowlink[node] = self.lowlink[node].min(self.lowlink[next]);
+                }
+                Some(next_index) if self.on_stack[next] => {
+                    self.lowlink[node] = self.lowlink[node].min(next_index);
+                }
+                _ => {}
+            }
+        }
+
+        if Some(self.lowlink[node]) == self.index[node] {
+            let mut component = vec![];
+            while let Some(member) = self.stack.pop() {
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            component.sort();
+            self.components.push(component);
+        }
+    }
+}
+
+// The arguments of an external call, which have to be bound before it
+fn external_inputs<'a>(decl: &'a Declaration, externals: &Externals) -> &'a [String] {
+    match externals.get(&decl.name).map(|external| external.kind) {
+        Some(ExternalKind::Functor) => &decl.params[..decl.params.len() - 1],
+        _ => &decl.params,
+    }
+}
+
+// GYO reduction of the hypergraph with the body declarations as edges over their
+// variables: variables in only one edge are removed, as are edges contained in another
+// edge. The body is acyclic if that reduces it to a single edge.
+fn is_cyclic(body: &[Declaration]) -> bool {
+    let mut edges = body
+        .iter()
+        .filter(|decl| !is_external(&decl.name))
+        .map(|decl| decl.params.iter().collect::<BTreeSet<_>>())
+        .collect::<Vec<_>>();
+    loop {
+        let mut reduced = false;
+        let lonely = edges
+            .iter()
+            .flatten()
+            .filter(|var| edges.iter().filter(|edge| edge.contains(*var)).count() == 1)
+            .copied()
+            .collect::<Vec<_>>();
+        for edge in &mut edges {
+            reduced |= edge.iter().any(|var| lonely.contains(var));
+            edge.retain(|var| !lonely.contains(var));
+        }
+        let contained = (0..edges.len())
+            .find(|i| (0..edges.len()).any(|j| *i != j && edges[*i].is_subset(&edges[j])));
+        if let Some(i) = contained {
+            edges.remove(i);
+            reduced = true;
+        }
+        if !reduced {
+            return edges.len() > 1;
+        }
+    }
+}
+
+// Greedy join order: start from the delta declaration, then always join the declaration
+// sharing the most parameters with what is already bound, so that cross products only
+// happen when there is no other choice. External calls happen as soon as their arguments
+// are bound.
+fn plan_variant(
+    body: &[Declaration],
+    delta_idx: usize,
+    externals: &Externals,
+    place: &str,
+) -> Result<Vec<JoinStep>, String> {
+    let mut bound = body[delta_idx].params.iter().collect::<HashSet<_>>();
+    let mut steps = vec![JoinStep {
+        body_idx: delta_idx,
+        delta: true,
+        keys: vec![],
+        cross_product: false,
+    }];
+    let mut remaining = (0..body.len())
+        .filter(|i| *i != delta_idx)
+        .collect::<Vec<_>>();
+
+    while !remaining.is_empty() {
+        let ready_external = remaining.iter().position(|i| {
+            is_external(&body[*i].name)
+                && external_inputs(&body[*i], externals)
+                    .iter()
+                    .all(|param| bound.contains(param))
+        });
+        if let Some(pos) = ready_external {
+            let body_idx = remaining.remove(pos);
+            let mut keys = vec![];
+            for param in external_inputs(&body[body_idx], externals) {
+                if !keys.contains(param) {
+                    keys.push(param.clone());
+                }
+            }
+            steps.push(JoinStep {
+                body_idx,
+                delta: false,
+                keys,
+                cross_product: false,
+            });
+            bound.extend(body[body_idx].params.iter());
+            continue;
+        }
+        if remaining.iter().all(|i| is_external(&body[*i].name)) {
+            return Err(format!(
+                "The arguments of external {} in {} are not bound by its body",
+                body[remaining[0]], place
+            ));
+        }
+
+        let shared = |i: &usize| {
+            let mut keys = vec![];
+            for param in &body[*i].params {
+                if bound.contains(param) && !keys.contains(param) {
+                    keys.push(param.clone());
+                }
+            }
+            keys
+        };
+        let (pos, _) = remaining
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| !is_external(&body[**i].name))
+            .max_by_key(|(pos, i)| (shared(i).len(), std::cmp::Reverse(*pos)))
+            .unwrap();
+        let body_idx = remaining.remove(pos);
+        let keys = shared(&body_idx);
+        steps.push(JoinStep {
+            body_idx,
+            delta: false,
+            cross_product: keys.is_empty(),
+            keys,
+        });
+        bound.extend(body[body_idx].params.iter());
+    }
+    Ok(steps)
+}
+
+pub fn plan_program(program: &Program) -> Result<Plan, String> {
+    plan_program_with_externals(program, &Externals::default())
+}
+
+// Plans a program that calls the given external predicates and functors
+pub fn plan_program_with_externals(
+    program: &Program,
+    externals: &Externals,
+) -> Result<Plan, String> {
+    verify_rules(program, externals)?;
+
+    let relation_idx = program
+        .decls
+        .iter()
+        .enumerate()
+        .map(|(i, decl)| (decl.name.as_str(), i))
+        .collect::<HashMap<_, _>>();
+
+    // head relation -> body relations it depends on
+    let mut edges: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    for rule in &program.rules {
+        let head = relation_idx[rule.head.name.as_str()];
+        for decl in rule.body.iter().filter(|decl| !is_external(&decl.name)) {
+            edges
+                .entry(head)
+                .or_default()
+                .insert(relation_idx[decl.name.as_str()]);
+        }
+    }
+
+    let num_relations = program.decls.len();
+    let mut finder = SccFinder {
+        edges: &edges,
+        index: vec![None; num_relations],
+        lowlink: vec![0; num_relations],
+        on_stack: vec![false; num_relations],
+        stack: vec![],
+        next_index: 0,
+        components: vec![],
+    };
+    for relation in 0..num_relations {
+        if finder.index[relation].is_none() {
+            finder.visit(relation);
+        }
+    }
+
+    let mut strata = vec![];
+    for component in finder.components {
+        let members = component.iter().collect::<HashSet<_>>();
+        let recursive = component.iter().any(|relation| {
+            edges
+                .get(relation)
+                .is_some_and(|deps| deps.iter().any(|dep| members.contains(dep)))
+        });
+        let rules = program
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| members.contains(&relation_idx[rule.head.name.as_str()]))
+            .map(|(rule_idx, rule)| {
+                // an external call never reads from the delta, so it gets no variant
+                Ok(RulePlan {
+                    rule: rule_idx,
+                    variants: (0..rule.body.len())
+                        .filter(|delta_idx| !is_external(&rule.body[*delta_idx].name))
+                        .map(|delta_idx| {
+                            let place = format!("rule {}", rule_idx);
+                            plan_variant(&rule.body, delta_idx, externals, &place)
+                        })
+                        .collect::<Result<_, String>>()?,
+                    cyclic: is_cyclic(&rule.body),
+                    leapfrog: false,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if rules.is_empty() {
+            continue;
+        }
+        strata.push(Stratum {
+            relations: component
+                .iter()
+                .map(|relation| program.decls[*relation].name.clone())
+                .collect(),
+            recursive,
+            rules,
+        });
+    }
+
+    let constraints = program
+        .constraints
+        .iter()
+        .enumerate()
+        .map(|(constraint_idx, constraint)| {
+            let first = constraint
+                .body
+                .iter()
+                .position(|decl| !is_external(&decl.name))
+                .unwrap();
+            let place = format!("constraint {}", constraint_idx);
+            let mut steps = plan_variant(&constraint.body, first, externals, &place)?;
+            // every declaration reads the full database
+            steps[0].delta = false;
+            Ok(steps)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let leapfrog_supported = program
+        .rules
+        .iter()
+        .map(|rule| {
+            rule.body.iter().all(|decl| {
+                !is_external(&decl.name) && externals.get_relation(&decl.name).is_none()
+            })
+        })
+        .collect();
+    Ok(Plan {
+        strata,
+        constraints,
+        rule_text: program.rules.iter().map(|rule| rule.to_string()).collect(),
+        body_text: program
+            .rules
+            .iter()
+            .map(|rule| rule.body.iter().map(|decl| decl.to_string()).collect())
+            .collect(),
+        constraint_text: program
+            .constraints
+            .iter()
+            .map(|constraint| constraint.to_string())
+            .collect(),
+        constraint_body_text: program
+            .constraints
+            .iter()
+            .map(|constraint| {
+                constraint
+                    .body
+                    .iter()
+                    .map(|decl| decl.to_string())
+                    .collect()
+            })
+            .collect(),
+        leapfrog_supported,
+        externals: externals.clone(),
+    }
+    .with_join_strategy(JoinStrategy::Auto))
+}
+
+// The evaluation plan of a program as text, without running it
+pub fn explain(program: &Program) -> Result<String, String> {
+    Ok(plan_program(program)?.to_string())
+}
+
+impl Plan {
+    // The joins of one join order, e.g. "scan delta a(x, y), hash join b(y, z) on [y]"
+    fn format_joins(&self, body: &[String], steps: &[JoinStep]) -> String {
+        let mut joins = vec![];
+        for step in steps {
+            let atom = &body[step.body_idx];
+            let name = atom.split('(').next().unwrap_or_default();
+            let source = if step.delta { "delta " } else { "" };
+            if let Some(external) = self.externals.get(name) {
+                let call = match external.kind {
+                    ExternalKind::Predicate => "filter",
+                    ExternalKind::Functor => "call",
+                };
+                joins.push(format!("{} {}", call, atom));
+            } else if joins.is_empty() {
+                joins.push(format!("scan {}{}", source, atom));
+            } else if step.cross_product {
+                joins.push(format!("cross product {}{}", source, atom));
+            } else if self.externals.get_relation(name).is_some() {
+                joins.push(format!(
+                    "probe external {} on [{}]",
+                    atom,
+                    step.keys.join(", ")
+                ));
+            } else {
+                joins.push(format!(
+                    "hash join {}{} on [{}]",
+                    source,
+                    atom,
+                    step.keys.join(", ")
+                ));
+            }
+        }
+        joins.join(", ")
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut cross_products = vec![];
+        for (stratum_idx, stratum) in self.strata.iter().enumerate() {
+            writeln!(
+                f,
+                "stratum {}{}: {}",
+                stratum_idx,
+                if stratum.recursive {
+                    " (recursive)"
+                } else {
+                    ""
+                },
+                stratum.relations.join(", ")
+            )?;
+            for rule_plan in &stratum.rules {
+                writeln!(
+                    f,
+                    "  rule {}{}: {}",
+                    rule_plan.rule,
+                    if rule_plan.cyclic { " (cyclic)" } else { "" },
+                    self.rule_text[rule_plan.rule]
+                )?;
+                for steps in &rule_plan.variants {
+                    let body = &self.body_text[rule_plan.rule];
+                    if rule_plan.leapfrog {
+                        let atoms = steps
+                            .iter()
+                            .map(|step| {
+                                let source = if step.delta { "delta " } else { "" };
+                                format!("{}{}", source, body[step.body_idx])
+                            })
+                            .collect::<Vec<_>>();
+                        writeln!(f, "    leapfrog triejoin {}", atoms.join(", "))?;
+                        continue;
+                    }
+                    for step in steps.iter().filter(|step| step.cross_product) {
+                        let atom = &body[step.body_idx];
+                        if !cross_products.contains(&(rule_plan.rule, atom)) {
+                            cross_products.push((rule_plan.rule, atom));
+                        }
+                    }
+                    writeln!(f, "    {}", self.format_joins(body, steps))?;
+                }
+            }
+        }
+        for (constraint_idx, steps) in self.constraints.iter().enumerate() {
+            writeln!(
+                f,
+                "constraint {}: {}",
+                constraint_idx, self.constraint_text[constraint_idx]
+            )?;
+            let body = &self.constraint_body_text[constraint_idx];
+            writeln!(f, "  {}", self.format_joins(body, steps))?;
+        }
+        for (rule_idx, atom) in cross_products {
+            writeln!(
+                f,
+                "warning: rule {} joins {} without shared parameters (cross product)",
+                rule_idx, atom
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::plan_program;
+    use crate::parse::{parse_program, Token};
+    use logos::Logos;
+
+    #[test]
+    fn test_plan_strata_and_join_order() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl start(x) .input;
+            .decl reachable(x, y) .output;
+            .decl from_start(y) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            .rule from_start(y) :- 3 reachable(x, y), edge(y, z), start(x);
+            "#,
+        ))
+        .unwrap();
+        let plan = plan_program(&program).unwrap();
+
+        assert_eq!(plan.strata.len(), 2);
+        assert_eq!(plan.strata[0].relations, vec!["reachable".to_string()]);
+        assert!(plan.strata[0].recursive);
+        assert_eq!(plan.strata[1].relations, vec!["from_start".to_string()]);
+        assert!(!plan.strata[1].recursive);
+
+        // starting from start(x), reachable(x, y) shares x and has to come before edge(y, z)
+        let from_start = &plan.strata[1].rules[0];
+        let order = from_start.variants[2]
+            .iter()
+            .map(|step| step.body_idx)
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![2, 0, 1]);
+        assert!(from_start.variants[2]
+            .iter()
+            .all(|step| !step.cross_product));
+    }
+
+    #[test]
+    fn test_plan_rejects_unbound_head() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl bad(x, y) .output;
+            .rule bad(x, w) :- 1 edge(x, y);
+            "#,
+        ))
+        .unwrap();
+        assert!(plan_program(&program).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::parse::Program;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Statistics for one rule in one iteration of run_semi_naive
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleProfile {
+    pub rule: usize,
+    pub time_ns: u64,
+    // for every semi-naive variant of the rule, the number of parameter mappings
+    // after each join of the body
+    pub join_sizes: Vec<Vec<usize>>,
+    pub new_facts: usize,
+    // facts derived by the rule that were already in the database
+    pub duplicate_facts: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IterationProfile {
+    pub iteration: usize,
+    pub stratum: usize,
+    pub rules: Vec<RuleProfile>,
+    // the size of every relation at the end of the iteration
+    pub relation_sizes: BTreeMap<String, usize>,
+}
+
+// Per rule and per iteration statistics collected by run_datalog_with_options
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+    pub rules: Vec<String>,
+    pub iterations: Vec<IterationProfile>,
+}
+
+impl Profile {
+    pub fn new(program: &Program) -> Profile {
+        Profile {
+            rules: program.rules.iter().map(|rule| rule.to_string()).collect(),
+            iterations: vec![],
+        }
+    }
+
+    pub(crate) fn start_iteration(&mut self, stratum: usize) {
+        self.iterations.push(IterationProfile {
+            iteration: self.iterations.len() + 1,
+            stratum,
+            rules: vec![],
+            relation_sizes: BTreeMap::new(),
+        });
+    }
+
+    pub(crate) fn record_rule(&mut self, rule: RuleProfile) {
+        if let Some(iteration) = self.iterations.last_mut() {
+            iteration.rules.push(rule);
+        }
+    }
+
+    pub(crate) fn finish_iteration(&mut self, relation_sizes: BTreeMap<String, usize>) {
+        if let Some(iteration) = self.iterations.last_mut() {
+            iteration.relation_sizes = relation_sizes;
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // Human-readable summary: totals per rule, then one line per rule and iteration
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let mut totals = vec![(0u64, 0usize, 0usize, 0usize); self.rules.len()];
+        for iteration in &self.iterations {
+            for rule in &iteration.rules {
+                let total = &mut totals[rule.rule];
+                total.0 += rule.time_ns;
+                total.1 += rule.new_facts;
+                total.2 += rule.duplicate_facts;
+                total.3 += rule.join_sizes.iter().flatten().sum::<usize>();
+            }
+        }
+
+        out.push_str(&format!("{} iterations\n\n", self.iterations.len()));
+        out.push_str("rule  time (ms)  new  duplicate  bindings\n");
+        for (rule_idx, (time_ns, new, duplicate, bindings)) in totals.iter().enumerate() {
+            out.push_str(&format!(
+                "{:<4}  {:>9.3}  {:>3}  {:>9}  {:>8}  {}\n",
+                rule_idx,
+                *time_ns as f64 / 1e6,
+                new,
+                duplicate,
+                bindings,
+                self.rules[rule_idx]
+            ));
+        }
+
+        out.push_str("\niteration  stratum  rule  time (ms)  new  duplicate  join sizes\n");
+        for iteration in &self.iterations {
+            for rule in &iteration.rules {
+                out.push_str(&format!(
+                    "{:<9}  {:<7}  {:<4}  {:>9.3}  {:>3}  {:>9}  {:?}\n",
+                    iteration.iteration,
+                    iteration.stratum,
+                    rule.rule,
+                    rule.time_ns as f64 / 1e6,
+                    rule.new_facts,
+                    rule.duplicate_facts,
+                    rule.join_sizes
+                ));
+            }
+            let sizes = iteration
+                .relation_sizes
+                .iter()
+                .map(|(name, size)| format!("{} {}", name, size))
+                .collect::<Vec<_>>();
+            out.push_str(&format!(
+                "{:<9}  relation sizes: {}\n",
+                "",
+                sizes.join(", ")
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::implem::{run_datalog_with_options, RunOptions};
+    use crate::parse::{parse_fact_vector, parse_program, Token};
+    use logos::Logos;
+    use serde_json::Value;
+
+    #[test]
+    fn test_profile_counts_and_json() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl reachable(x, y) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            "#,
+        ))
+        .unwrap();
+        let facts =
+            parse_fact_vector(&mut Token::lexer("3 edge(a, b); edge(b, c); edge(c, d);")).unwrap();
+        let options = RunOptions {
+            profile: true,
+            ..RunOptions::default()
+        };
+        let profile = run_datalog_with_options(&program, facts, &options)
+            .unwrap()
+            .profile
+            .unwrap();
+
+        // every rule is profiled in every iteration of its stratum, and the new facts add
+        // up to the derived ones: the 3 edges, then the paths of length 2 and 3
+        let new_facts = |rule: usize| {
+            profile
+                .iterations
+                .iter()
+                .flat_map(|iteration| &iteration.rules)
+                .filter(|profile| profile.rule == rule)
+                .map(|profile| profile.new_facts)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(new_facts(0).iter().sum::<usize>(), 3);
+        assert_eq!(new_facts(1).iter().sum::<usize>(), 3);
+        let last = profile.iterations.last().unwrap();
+        assert_eq!(last.relation_sizes["reachable"], 6);
+        assert!(last.rules.iter().all(|rule| rule.new_facts == 0));
+        assert!(profile
+            .report()
+            .starts_with(&format!("{} iterations\n", profile.iterations.len())));
+
+        let json: Value = serde_json::from_str(&profile.to_json()).unwrap();
+        assert_eq!(json["rules"].as_array().unwrap().len(), 2);
+        let iterations = json["iterations"].as_array().unwrap();
+        assert_eq!(iterations.len(), profile.iterations.len());
+        assert_eq!(iterations[0]["iteration"], 1);
+        let rule = &iterations[0]["rules"][0];
+        for field in [
+            "rule",
+            "time_ns",
+            "join_sizes",
+            "new_facts",
+            "duplicate_facts",
+        ] {
+            assert!(rule.get(field).is_some(), "{} missing", field);
+        }
+        assert_eq!(iterations.last().unwrap()["relation_sizes"]["reachable"], 6);
+    }
+}
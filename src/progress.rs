@@ -0,0 +1,33 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Reported by run_datalog_with_progress at the end of every iteration
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    // counted over all strata, starting at 1
+    pub iteration: usize,
+    pub stratum: usize,
+    pub relation_sizes: BTreeMap<String, usize>,
+}
+
+// Asks a running evaluation to stop. Clones share the same flag, so one clone can be handed
+// to the evaluation and another kept to cancel it. The flag is checked between rule
+// applications, so a single expensive rule still runs to completion.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
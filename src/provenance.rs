@@ -0,0 +1,135 @@
+use crate::database::EqRel;
+use crate::parse::{Fact, Program};
+use std::collections::HashMap;
+
+// How a fact first made it into the database
+#[derive(Debug, Clone, PartialEq)]
+pub enum Derivation {
+    Input,
+    // a pair of an .eqrel relation implied by the pairs before it
+    Equivalence,
+    // the value of a lattice-valued relation joined from the values derived for its key
+    LatticeJoin,
+    // the index of the rule in the program and the body facts it was joined from
+    Rule { rule: usize, body: Vec<Fact> },
+}
+
+// Why-provenance collected by run_datalog_with_options. Only the first derivation of each
+// fact is kept; its body facts were all derived in earlier rounds, so following the
+// derivations always bottoms out in input facts.
+#[derive(Debug)]
+pub struct Provenance {
+    rules: Vec<String>,
+    derivations: HashMap<Fact, Derivation>,
+    // the classes of the recorded pairs of every .eqrel relation, since only the pairs that
+    // changed a class are recorded and the pairs they imply are not
+    eqrels: HashMap<String, EqRel>,
+}
+
+static EQUIVALENCE: Derivation = Derivation::Equivalence;
+
+impl Provenance {
+    pub fn new(program: &Program) -> Provenance {
+        Provenance {
+            rules: program.rules.iter().map(|rule| rule.to_string()).collect(),
+            derivations: HashMap::new(),
+            eqrels: program
+                .decls
+                .iter()
+                .filter(|decl| decl.eqrel)
+                .map(|decl| (decl.name.clone(), EqRel::default()))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, fact: Fact, derivation: Derivation) {
+        if let Some(eqrel) = self.eqrels.get_mut(&fact.name) {
+            eqrel.union(&fact.params[0], &fact.params[1], &mut vec![]);
+        }
+        self.derivations.entry(fact).or_insert(derivation);
+    }
+
+    pub fn derivation(&self, fact: &Fact) -> Option<&Derivation> {
+        self.derivations.get(fact).or_else(|| {
+            let eqrel = self.eqrels.get(&fact.name)?;
+            match &fact.params[..] {
+                [x, y] if eqrel.same_class(x, y) => Some(&EQUIVALENCE),
+                _ => None,
+            }
+        })
+    }
+
+    // Prints the derivation tree of a fact, or None if the fact is not in the database
+    pub fn explain(&self, fact: &Fact) -> Option<String> {
+        self.derivation(fact)?;
+        let mut out = String::new();
+        self.explain_into(fact, 0, &mut out);
+        Some(out)
+    }
+
+    fn explain_into(&self, fact: &Fact, depth: usize, out: &mut String) {
+        let indent = "  ".repeat(depth);
+        match self.derivation(fact) {
+            Some(Derivation::Rule { rule, body }) => {
+                out.push_str(&format!(
+                    "{}{} <- rule {}: {}\n",
+                    indent, fact, rule, self.rules[*rule]
+                ));
+                for body_fact in body {
+                    self.explain_into(body_fact, depth + 1, out);
+                }
+            }
+            Some(Derivation::Input) => {
+                out.push_str(&format!("{}{} <- input\n", indent, fact));
+            }
+            Some(Derivation::Equivalence) => {
+                out.push_str(&format!("{}{} <- equivalence\n", indent, fact));
+            }
+            Some(Derivation::LatticeJoin) => {
+                out.push_str(&format!("{}{} <- lattice join\n", indent, fact));
+            }
+            None => {
+                out.push_str(&format!("{}{} <- unknown\n", indent, fact));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::implem::{run_datalog_with_options, RunOptions};
+    use crate::parse::{parse_fact, parse_fact_vector, parse_program, Token};
+    use logos::Logos;
+
+    #[test]
+    fn test_explain_reachable() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl reachable(x, y) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            "#,
+        ))
+        .unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer("2 edge(a, b); edge(b, c);")).unwrap();
+        let options = RunOptions {
+            provenance: true,
+            ..RunOptions::default()
+        };
+        let output = run_datalog_with_options(&program, facts, &options).unwrap();
+        let provenance = output.provenance.unwrap();
+
+        let fact = parse_fact(&mut Token::lexer("reachable(a, c)")).unwrap();
+        assert_eq!(
+            provenance.explain(&fact).unwrap(),
+            "reachable(a, c) <- rule 1: reachable(x, z) :- reachable(x, y), edge(y, z)\n\
+             \x20 reachable(a, b) <- rule 0: reachable(x, y) :- edge(x, y)\n\
+             \x20   edge(a, b) <- input\n\
+             \x20 edge(b, c) <- input\n"
+        );
+
+        let missing = parse_fact(&mut Token::lexer("reachable(c, a)")).unwrap();
+        assert!(provenance.explain(&missing).is_none());
+    }
+}
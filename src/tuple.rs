@@ -0,0 +1,77 @@
+// Conversion between Rust values and the string parameters of facts, used by the typed
+// Relation handles of Engine
+
+// A single parameter of a fact
+pub trait Value: Sized {
+    fn to_param(&self) -> String;
+    fn from_param(param: &str) -> Result<Self, String>;
+}
+
+impl Value for String {
+    fn to_param(&self) -> String {
+        self.clone()
+    }
+
+    fn from_param(param: &str) -> Result<String, String> {
+        Ok(param.to_string())
+    }
+}
+
+macro_rules! impl_value_from_str {
+    ($($ty:ty),*) => {
+        $(
+            impl Value for $ty {
+                fn to_param(&self) -> String {
+                    self.to_string()
+                }
+
+                fn from_param(param: &str) -> Result<$ty, String> {
+                    param.parse().map_err(|_| {
+                        format!("Parameter {} is not a valid {}", param, stringify!($ty))
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_value_from_str!(i32, i64, u32, u64, usize, bool);
+
+// The parameters of a fact as a tuple of values, e.g. (String, String) for edge(x, y)
+pub trait Tuple: Sized {
+    const ARITY: usize;
+    fn to_params(&self) -> Vec<String>;
+    fn from_params(params: &[String]) -> Result<Self, String>;
+}
+
+macro_rules! impl_tuple {
+    ($arity:expr; $($name:ident $idx:tt),*) => {
+        impl<$($name: Value),*> Tuple for ($($name,)*) {
+            const ARITY: usize = $arity;
+
+            fn to_params(&self) -> Vec<String> {
+                vec![$(self.$idx.to_param()),*]
+            }
+
+            fn from_params(params: &[String]) -> Result<Self, String> {
+                if params.len() != $arity {
+                    return Err(format!(
+                        "Expected {} parameters, got {}",
+                        $arity,
+                        params.len()
+                    ));
+                }
+                Ok(($($name::from_param(&params[$idx])?,)*))
+            }
+        }
+    };
+}
+
+impl_tuple!(1; A 0);
+impl_tuple!(2; A 0, B 1);
+impl_tuple!(3; A 0, B 1, C 2);
+impl_tuple!(4; A 0, B 1, C 2, D 3);
+impl_tuple!(5; A 0, B 1, C 2, D 3, E 4);
+impl_tuple!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+impl_tuple!(7; A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_tuple!(8; A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
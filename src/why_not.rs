@@ -0,0 +1,465 @@
+use crate::database::Database;
+use crate::external::Externals;
+use crate::implem::{call_external, join_external_relation, ParameterMapping};
+use crate::parse::{DeclKind, Declaration, Fact, Program, Rule};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+// how many failed instantiations are reported for a single rule before giving up
+const MAX_FAILURES_PER_RULE: usize = 8;
+
+// Explanation of why a ground fact is not in the database
+#[derive(Debug)]
+pub enum WhyNot {
+    // the fact is in the database after all
+    Holds(Fact),
+    // the fact is already being explained further up the tree
+    Recursive(Fact),
+    Missing {
+        fact: Fact,
+        // the fact belongs to an input relation that no rule derives, so it could only have
+        // been given directly
+        input: bool,
+        rules: Vec<RuleAttempt>,
+    },
+}
+
+// A rule whose head could have produced the goal
+#[derive(Debug)]
+pub struct RuleAttempt {
+    pub rule: usize,
+    pub text: String,
+    // false if the head cannot be instantiated to the goal at all,
+    // e.g. `p(x, x)` for the goal `p(a, b)`
+    pub unifies: bool,
+    pub failures: Vec<AtomFailure>,
+    // more instantiations failed than MAX_FAILURES_PER_RULE
+    pub truncated: bool,
+}
+
+// A body declaration for which no fact matched, given the bindings made so far
+#[derive(Debug)]
+pub struct AtomFailure {
+    pub body_idx: usize,
+    pub bindings: BTreeMap<String, String>,
+    // the body declaration with bound parameters filled in
+    pub pattern: Vec<Option<String>>,
+    pub name: String,
+    // an external predicate, functor or relation, whose facts are not in the database
+    pub external: bool,
+    // when every parameter is bound, the explanation of the missing fact
+    pub missing: Option<Box<WhyNot>>,
+}
+
+struct Context<'a> {
+    program: &'a Program,
+    externals: &'a Externals,
+    database: &'a Database,
+    input_relations: HashSet<&'a str>,
+}
+
+// Why-not analysis: explains why `goal` is not in `database` (the result of running the
+// program with RunOptions::all_relations, so that the input relations are there too) by
+// trying every rule whose head unifies with it and reporting, per body declaration, the
+// instantiations that had no matching fact.
+pub fn why_not(program: &Program, database: &Database, goal: &Fact) -> Result<WhyNot, String> {
+    why_not_with_externals(program, &Externals::new(), database, goal)
+}
+
+// why_not for a program that calls externals, which have to be the ones it was run with
+pub fn why_not_with_externals(
+    program: &Program,
+    externals: &Externals,
+    database: &Database,
+    goal: &Fact,
+) -> Result<WhyNot, String> {
+    let Some(decl) = program.decls.iter().find(|decl| decl.name == goal.name) else {
+        return Err(format!("Fact {} not declared", goal.name));
+    };
+    if decl.params.len() != goal.params.len() {
+        return Err(format!(
+            "Fact {} has the wrong number of parameters",
+            goal.name
+        ));
+    }
+
+    let context = Context {
+        program,
+        externals,
+        database,
+        // a declaration without .output is an input by default, also when rules derive it
+        input_relations: program
+            .decls
+            .iter()
+            .filter(|decl| decl.kind == DeclKind::Input)
+            .filter(|decl| program.rules.iter().all(|rule| rule.head.name != decl.name))
+            .map(|decl| decl.name.as_str())
+            .collect(),
+    };
+    Ok(explain_missing(&context, goal, &mut vec![]))
+}
+
+fn explain_missing(context: &Context, goal: &Fact, path: &mut Vec<Fact>) -> WhyNot {
+    if context.database.contains(goal) {
+        return WhyNot::Holds(goal.clone());
+    }
+    if path.contains(goal) {
+        return WhyNot::Recursive(goal.clone());
+    }
+
+    path.push(goal.clone());
+    let mut rules = vec![];
+    for (rule_idx, rule) in context.program.rules.iter().enumerate() {
+        if rule.head.name != goal.name {
+            continue;
+        }
+        let mut attempt = RuleAttempt {
+            rule: rule_idx,
+            text: rule.to_string(),
+            unifies: true,
+            failures: vec![],
+            truncated: false,
+        };
+        match bind_params(
+            &BTreeMap::new(),
+            &rule.head.params,
+            goal.params.iter().map(String::as_str),
+        ) {
+            Some(bindings) => {
+                let order = search_order(rule, context.externals);
+                search_body(context, rule_idx, &order, bindings, path, &mut attempt)
+            }
+            None => attempt.unifies = false,
+        }
+        rules.push(attempt);
+    }
+    path.pop();
+
+    WhyNot::Missing {
+        fact: goal.clone(),
+        input: context.input_relations.contains(goal.name.as_str()),
+        rules,
+    }
+}
+
+// extends the bindings so that `params` takes the values `values`,
+// or None if a parameter is already bound to something else
+fn bind_params<'a>(
+    bindings: &BTreeMap<String, String>,
+    params: &[String],
+    values: impl IntoIterator<Item = &'a str>,
+) -> Option<BTreeMap<String, String>> {
+    let mut bindings = bindings.clone();
+    for (param, value) in params.iter().zip(values) {
+        match bindings.get(param) {
+            Some(bound) if bound != value => return None,
+            Some(_) => {}
+            None => {
+                bindings.insert(param.clone(), value.to_string());
+            }
+        }
+    }
+    Some(bindings)
+}
+
+// The body declarations in the order they are searched: as written, except that an
+// external call waits until its arguments are bound, by the head or the declarations before
+fn search_order(rule: &Rule, externals: &Externals) -> Vec<usize> {
+    let mut bound = rule.head.params.iter().collect::<HashSet<_>>();
+    let mut order = vec![];
+    let mut waiting = vec![];
+    for body_idx in 0..rule.body.len() {
+        waiting.push(body_idx);
+        while let Some(position) = waiting.iter().position(|body_idx| {
+            let decl = &rule.body[*body_idx];
+            externals.get(&decl.name).is_none_or(|external| {
+                decl.params[..external.arity]
+                    .iter()
+                    .all(|param| bound.contains(param))
+            })
+        }) {
+            let body_idx = waiting.remove(position);
+            bound.extend(rule.body[body_idx].params.iter());
+            order.push(body_idx);
+        }
+    }
+    order.extend(waiting);
+    order
+}
+
+// The bindings that extend `bindings` with a match of the body declaration `decl`: a fact
+// of the database, a tuple of an external relation, or a call of an external that holds
+fn match_decl(
+    context: &Context,
+    decl: &Declaration,
+    bindings: &BTreeMap<String, String>,
+) -> Vec<BTreeMap<String, String>> {
+    let mapping = ParameterMapping {
+        parameter_keys: bindings.keys().cloned().collect(),
+        parameter_maps: [bindings.clone()].into_iter().collect(),
+    };
+    if let Some(external) = context.externals.get(&decl.name) {
+        // an argument left unbound by the whole body, which planning rejects
+        if !decl.params[..external.arity]
+            .iter()
+            .all(|param| bindings.contains_key(param))
+        {
+            return vec![];
+        }
+        return call_external(external, decl, &mapping)
+            .parameter_maps
+            .into_iter()
+            .collect();
+    }
+    let mut matches = context
+        .database
+        .relation(&decl.name)
+        .filter_map(|fact| bind_params(bindings, &decl.params, fact.params()))
+        .collect::<Vec<_>>();
+    if let Some(relation) = context.externals.get_relation(&decl.name) {
+        matches.extend(join_external_relation(relation, decl, &mapping).parameter_maps);
+    }
+    matches
+}
+
+fn search_body(
+    context: &Context,
+    rule_idx: usize,
+    order: &[usize],
+    bindings: BTreeMap<String, String>,
+    path: &mut Vec<Fact>,
+    attempt: &mut RuleAttempt,
+) {
+    let rule = &context.program.rules[rule_idx];
+    let Some((&body_idx, rest)) = order.split_first() else {
+        return;
+    };
+    if attempt.truncated {
+        return;
+    }
+
+    let decl = &rule.body[body_idx];
+    let matches = match_decl(context, decl, &bindings);
+
+    if matches.is_empty() {
+        if attempt.failures.len() == MAX_FAILURES_PER_RULE {
+            attempt.truncated = true;
+            return;
+        }
+        let pattern = decl
+            .params
+            .iter()
+            .map(|param| bindings.get(param).cloned())
+            .collect::<Vec<_>>();
+        let external = context.externals.get(&decl.name).is_some()
+            || context.externals.get_relation(&decl.name).is_some();
+        // an external can't be explained any further
+        let missing = if !external && pattern.iter().all(|value| value.is_some()) {
+            let fact = Fact {
+                name: decl.name.clone(),
+                params: pattern.iter().flatten().cloned().collect(),
+            };
+            Some(Box::new(explain_missing(context, &fact, path)))
+        } else {
+            None
+        };
+        attempt.failures.push(AtomFailure {
+            body_idx,
+            bindings,
+            pattern,
+            name: decl.name.clone(),
+            external,
+            missing,
+        });
+        return;
+    }
+
+    for bindings in matches {
+        search_body(context, rule_idx, rest, bindings, path, attempt);
+    }
+}
+
+impl AtomFailure {
+    fn pattern_string(&self) -> String {
+        let params = self
+            .pattern
+            .iter()
+            .map(|value| value.clone().unwrap_or("_".to_string()))
+            .collect::<Vec<_>>();
+        format!("{}({})", self.name, params.join(", "))
+    }
+}
+
+impl WhyNot {
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let indent = "  ".repeat(depth);
+        match self {
+            WhyNot::Holds(fact) => writeln!(f, "{}{} holds", indent, fact),
+            WhyNot::Recursive(fact) => {
+                writeln!(f, "{}{} is not derived (see above)", indent, fact)
+            }
+            WhyNot::Missing { fact, input, rules } => {
+                if *input {
+                    return writeln!(f, "{}{} is a missing input fact", indent, fact);
+                }
+                writeln!(f, "{}{} is not derived", indent, fact)?;
+                if rules.is_empty() {
+                    writeln!(f, "{}  no rule derives {}", indent, fact.name)?;
+                }
+                for attempt in rules {
+                    writeln!(f, "{}  rule {}: {}", indent, attempt.rule, attempt.text)?;
+                    if !attempt.unifies {
+                        writeln!(f, "{}    the head does not match {}", indent, fact)?;
+                    }
+                    for failure in &attempt.failures {
+                        let bound = failure
+                            .bindings
+                            .iter()
+                            .map(|(param, value)| format!("{} = {}", param, value))
+                            .collect::<Vec<_>>();
+                        let reason = if failure.external {
+                            format!("{} does not hold", failure.pattern_string())
+                        } else {
+                            format!("no fact matches {}", failure.pattern_string())
+                        };
+                        writeln!(f, "{}    with {}: {}", indent, bound.join(", "), reason)?;
+                        if let Some(missing) = &failure.missing {
+                            missing.fmt_indented(f, depth + 3)?;
+                        }
+                    }
+                    if attempt.truncated {
+                        writeln!(f, "{}    ...", indent)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for WhyNot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{why_not, why_not_with_externals, WhyNot};
+    use crate::external::Externals;
+    use crate::implem::{run_datalog_with_options, RunOptions};
+    use crate::parse::{parse_fact, parse_fact_vector, parse_program, Token};
+    use logos::Logos;
+
+    #[test]
+    fn test_why_not_reachable() {
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl reachable(x, y) .output;
+            .rule reachable(x, y) :- 1 edge(x, y);
+            .rule reachable(x, z) :- 2 reachable(x, y), edge(y, z);
+            "#,
+        ))
+        .unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer("2 edge(a, b); edge(c, d);")).unwrap();
+        let options = RunOptions {
+            all_relations: true,
+            ..RunOptions::default()
+        };
+        let output = run_datalog_with_options(&program, facts, &options)
+            .unwrap()
+            .database;
+
+        let goal = parse_fact(&mut Token::lexer("reachable(a, d)")).unwrap();
+        let explanation = why_not(&program, &output, &goal).unwrap();
+        let WhyNot::Missing { rules, .. } = &explanation else {
+            panic!("reachable(a, d) should not hold");
+        };
+        assert_eq!(rules.len(), 2);
+        // edge(a, d) does not exist, and neither does edge(b, d) after reachable(a, b)
+        assert_eq!(rules[0].failures[0].pattern_string(), "edge(a, d)");
+        assert_eq!(rules[1].failures[0].pattern_string(), "edge(b, d)");
+        assert!(matches!(
+            rules[1].failures[0].missing.as_deref(),
+            Some(WhyNot::Missing { input: true, .. })
+        ));
+
+        assert_eq!(
+            explanation.to_string(),
+            "reachable(a, d) is not derived\n\
+             \x20 rule 0: reachable(x, y) :- edge(x, y)\n\
+             \x20   with x = a, y = d: no fact matches edge(a, d)\n\
+             \x20     edge(a, d) is a missing input fact\n\
+             \x20 rule 1: reachable(x, z) :- reachable(x, y), edge(y, z)\n\
+             \x20   with x = a, y = b, z = d: no fact matches edge(b, d)\n\
+             \x20     edge(b, d) is a missing input fact\n"
+        );
+
+        // an intermediate relation without flags is derived, not an input
+        let staged = parse_program(&mut Token::lexer(
+            r#"
+            .decl edge(x, y) .input;
+            .decl step(x, y);
+            .decl twice(x, z) .output;
+            .rule step(x, y) :- 1 edge(x, y);
+            .rule twice(x, z) :- 2 step(x, y), step(y, z);
+            "#,
+        ))
+        .unwrap();
+        let facts = parse_fact_vector(&mut Token::lexer("1 edge(a, b);")).unwrap();
+        let database = run_datalog_with_options(&staged, facts, &options)
+            .unwrap()
+            .database;
+        let goal = parse_fact(&mut Token::lexer("step(b, c)")).unwrap();
+        let explanation = why_not(&staged, &database, &goal).unwrap();
+        assert!(matches!(explanation, WhyNot::Missing { input: false, .. }));
+        assert!(explanation
+            .to_string()
+            .starts_with("step(b, c) is not derived\n  rule 0: step(x, y) :- edge(x, y)\n"));
+
+        let holds = parse_fact(&mut Token::lexer("reachable(a, b)")).unwrap();
+        assert!(matches!(
+            why_not(&program, &output, &holds).unwrap(),
+            WhyNot::Holds(_)
+        ));
+    }
+
+    #[test]
+    fn test_why_not_external() {
+        // the functor comes first, but is only called once item binds x
+        let program = parse_program(&mut Token::lexer(
+            r#"
+            .decl item(x) .input;
+            .decl named(x, y) .output;
+            .rule named(x, y) :- 2 @upper(x, y), item(x);
+            "#,
+        ))
+        .unwrap();
+        let mut externals = Externals::new();
+        externals.functor("upper", 1, |args| args[0].to_uppercase());
+        let facts = parse_fact_vector(&mut Token::lexer("1 item(a);")).unwrap();
+        let options = RunOptions {
+            all_relations: true,
+            externals: externals.clone(),
+            ..RunOptions::default()
+        };
+        let database = run_datalog_with_options(&program, facts, &options)
+            .unwrap()
+            .database;
+
+        let goal = parse_fact(&mut Token::lexer("named(a, b)")).unwrap();
+        let explanation = why_not_with_externals(&program, &externals, &database, &goal).unwrap();
+        assert_eq!(
+            explanation.to_string(),
+            "named(a, b) is not derived\n\
+             \x20 rule 0: named(x, y) :- @upper(x, y), item(x)\n\
+             \x20   with x = a, y = b: @upper(a, b) does not hold\n"
+        );
+        let goal = parse_fact(&mut Token::lexer("named(c, C)")).unwrap();
+        let explanation = why_not_with_externals(&program, &externals, &database, &goal).unwrap();
+        assert!(explanation
+            .to_string()
+            .contains("no fact matches item(c)\n      item(c) is a missing input fact\n"));
+    }
+}
@@ -0,0 +1,25 @@
+// Runs the datalogint binary the way it is used from the command line
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn test_plan_lattice_program() {
+    // the lattice is registered by the command line, not by the program
+    let program = env!("CARGO_TARGET_TMPDIR").to_string() + "/constants.dl";
+    fs::write(
+        &program,
+        r#"
+        .decl assign(v, c) .input;
+        .decl val(v, c: lattice<ConstLattice>) .output;
+        .rule val(v, c) :- 1 assign(v, c);
+        "#,
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_datalogint"))
+        .args(["run", &program, "--plan"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("val"));
+}
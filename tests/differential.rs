@@ -0,0 +1,208 @@
+// Differential test: random safe programs on random facts, evaluated with run_datalog and
+// with every join strategy and a few workers, have to give exactly the facts of a
+// deliberately naive reference evaluator. The programs are small, over a domain of a few
+// constants, so that every fixpoint is reached in a few iterations. A failing case prints
+// its seed, program and facts.
+#[path = "../benches/common/mod.rs"]
+mod common;
+
+use common::Rng;
+use datalogint::parse::{parse_program, Declaration, Rule};
+use datalogint::{
+    run_datalog, run_datalog_with_options, Fact, FactRef, JoinStrategy, Program, RunOptions, Token,
+};
+use logos::Logos;
+use std::collections::{BTreeMap, BTreeSet};
+
+const CASES: u64 = 300;
+const DOMAIN: u64 = 4;
+const VARIABLES: [&str; 4] = ["x", "y", "z", "w"];
+
+type FactSet = BTreeSet<(String, Vec<String>)>;
+
+// Input relations e0.. and derived relations d0.., each of arity 1 to 3. Rules only derive
+// d relations, from 1 to 3 body declarations of any relation (so rules can be recursive,
+// have repeated variables and cross products), with a head over the body's variables.
+fn random_program(rng: &mut Rng) -> (String, Vec<(String, usize)>) {
+    let inputs = (0..1 + rng.below(3))
+        .map(|i| (format!("e{}", i), 1 + rng.below_usize(3)))
+        .collect::<Vec<_>>();
+    let derived = (0..1 + rng.below(3))
+        .map(|i| (format!("d{}", i), 1 + rng.below_usize(3)))
+        .collect::<Vec<_>>();
+    let relations = inputs.iter().chain(&derived).collect::<Vec<_>>();
+
+    let mut src = String::new();
+    let decl = |(name, arity): &(String, usize), kind: &str| {
+        let params = (0..*arity).map(|i| format!("p{}", i)).collect::<Vec<_>>();
+        format!(".decl {}({}) {};\n", name, params.join(", "), kind)
+    };
+    for relation in &inputs {
+        src += &decl(relation, ".input");
+    }
+    for relation in &derived {
+        src += &decl(relation, ".output");
+    }
+    for _ in 0..1 + rng.below(4) {
+        let body = (0..1 + rng.below(3))
+            .map(|_| {
+                let (name, arity) = relations[rng.below_usize(relations.len())];
+                let params = (0..*arity)
+                    .map(|_| VARIABLES[rng.below_usize(VARIABLES.len())])
+                    .collect::<Vec<_>>();
+                (name.clone(), params)
+            })
+            .collect::<Vec<_>>();
+        let bound = body
+            .iter()
+            .flat_map(|(_, params)| params.iter().copied())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let (head, arity) = &derived[rng.below_usize(derived.len())];
+        let head_params = (0..*arity)
+            .map(|_| bound[rng.below_usize(bound.len())])
+            .collect::<Vec<_>>();
+        let body = body
+            .iter()
+            .map(|(name, params)| format!("{}({})", name, params.join(", ")))
+            .collect::<Vec<_>>();
+        src += &format!(
+            ".rule {}({}) :- {} {};\n",
+            head,
+            head_params.join(", "),
+            body.len(),
+            body.join(", ")
+        );
+    }
+    (src, inputs)
+}
+
+fn random_facts(rng: &mut Rng, inputs: &[(String, usize)]) -> Vec<Fact> {
+    let mut facts = vec![];
+    for (name, arity) in inputs {
+        // duplicates are part of the input on purpose
+        for _ in 0..rng.below(3 * DOMAIN) {
+            facts.push(Fact {
+                name: name.clone(),
+                params: (0..*arity)
+                    .map(|_| format!("c{}", rng.below(DOMAIN)))
+                    .collect(),
+            });
+        }
+    }
+    facts
+}
+
+// Every way to match the body declarations against the facts, extending `binding`
+fn match_body(
+    body: &[Declaration],
+    facts: &FactSet,
+    binding: &BTreeMap<String, String>,
+    matches: &mut Vec<BTreeMap<String, String>>,
+) {
+    let Some((decl, rest)) = body.split_first() else {
+        matches.push(binding.clone());
+        return;
+    };
+    for (name, params) in facts {
+        if *name != decl.name || params.len() != decl.params.len() {
+            continue;
+        }
+        let mut extended = binding.clone();
+        let consistent = decl.params.iter().zip(params).all(|(variable, value)| {
+            extended
+                .entry(variable.clone())
+                .or_insert_with(|| value.clone())
+                == value
+        });
+        if consistent {
+            match_body(rest, facts, &extended, matches);
+        }
+    }
+}
+
+// Naive evaluation: apply every rule to all facts until nothing new is derived
+fn reference(program: &Program, input: &[Fact]) -> FactSet {
+    let mut facts = input
+        .iter()
+        .map(|fact| (fact.name.clone(), fact.params.clone()))
+        .collect::<FactSet>();
+    loop {
+        let mut derived = vec![];
+        for Rule { head, body } in &program.rules {
+            let mut matches = vec![];
+            match_body(body, &facts, &BTreeMap::new(), &mut matches);
+            for binding in matches {
+                let params = head.params.iter().map(|p| binding[p].clone()).collect();
+                derived.push((head.name.clone(), params));
+            }
+        }
+        let len = facts.len();
+        facts.extend(derived);
+        if facts.len() == len {
+            break;
+        }
+    }
+    let outputs = program
+        .rules
+        .iter()
+        .map(|rule| rule.head.name.as_str())
+        .collect::<BTreeSet<_>>();
+    facts
+        .into_iter()
+        .filter(|(name, _)| outputs.contains(name.as_str()))
+        .collect()
+}
+
+fn to_set<'a>(facts: impl Iterator<Item = FactRef<'a>>) -> FactSet {
+    facts
+        .map(|fact| {
+            let fact = fact.to_fact();
+            (fact.name, fact.params)
+        })
+        .collect()
+}
+
+#[test]
+fn test_matches_reference_evaluator() {
+    for seed in 0..CASES {
+        let mut rng = Rng::with_seed(seed);
+        let (src, inputs) = random_program(&mut rng);
+        let facts = random_facts(&mut rng, &inputs);
+        let program = parse_program(&mut Token::lexer(&src)).unwrap();
+        let expected = reference(&program, &facts);
+        let context = || {
+            let facts = facts.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+            format!("seed {}\n{}facts: {}", seed, src, facts.join("; "))
+        };
+
+        let database = run_datalog(&program, facts.clone()).unwrap();
+        assert_eq!(to_set(database.facts()), expected, "{}", context());
+        // the facts are far fewer than the default partition_facts, so with 2 workers every
+        // delta is split only because of the lower threshold
+        for (join_strategy, workers) in [
+            (JoinStrategy::Hash, 1),
+            (JoinStrategy::Leapfrog, 1),
+            (JoinStrategy::Auto, 2),
+        ] {
+            let options = RunOptions {
+                join_strategy,
+                workers,
+                partition_facts: Some(1),
+                ..RunOptions::default()
+            };
+            let database = run_datalog_with_options(&program, facts.clone(), &options)
+                .unwrap()
+                .database;
+            assert_eq!(
+                to_set(database.facts()),
+                expected,
+                "{:?} with {} workers\n{}",
+                join_strategy,
+                workers,
+                context()
+            );
+        }
+    }
+}
@@ -0,0 +1,205 @@
+// Golden tests: every program `foo.dl` under samples/ is run on its inputs and the sorted
+// output relations are compared with the `.expected` file next to each input. The inputs of
+// `foo.dl` are the fact files `foo.in` and `foo_*.in` in its directory, and the Bril
+// programs (`.json`) in its directory if it is the only program there, whose functions are
+// turned into facts and evaluated one at a time.
+//
+// Run with `cargo test --test golden`, optionally followed by `-- --filter <filter>` to run
+// only the inputs whose path contains it. `-- --bless [<filter>]` writes the current output
+// as the expectation instead, for new inputs or after an intended change. A filter that
+// matches no input is an error. Other arguments, like the flags cargo test passes to every
+// test binary, are ignored.
+use datalogint::parse::{parse_fact_vector, parse_program};
+use datalogint::parse_bril::get_facts_from_bril_fn;
+use datalogint::{parse_bril, run_datalog_with_options, Fact, Program, RunOptions, Token};
+use logos::Logos;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+struct Case {
+    program: PathBuf,
+    input: PathBuf,
+}
+
+impl Case {
+    fn expected(&self) -> PathBuf {
+        self.input.with_extension("expected")
+    }
+}
+
+fn find_programs(dir: &Path, programs: &mut Vec<PathBuf>) {
+    let mut entries = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_programs(&path, programs);
+        } else if path.extension().is_some_and(|ext| ext == "dl") {
+            programs.push(path);
+        }
+    }
+}
+
+fn find_cases(root: &Path) -> Vec<Case> {
+    let mut programs = vec![];
+    find_programs(root, &mut programs);
+    let mut cases = vec![];
+    for program in &programs {
+        let dir = program.parent().unwrap();
+        let stem = program.file_stem().unwrap().to_str().unwrap();
+        let only_program = programs.iter().filter(|p| p.parent() == Some(dir)).count() == 1;
+        let mut inputs = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let name = path.file_stem().unwrap().to_str().unwrap();
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("in") => name == stem || name.starts_with(&format!("{}_", stem)),
+                    Some("json") => only_program,
+                    _ => false,
+                }
+            })
+            .collect::<Vec<_>>();
+        inputs.sort();
+        cases.extend(inputs.into_iter().map(|input| Case {
+            program: program.clone(),
+            input,
+        }));
+    }
+    cases
+}
+
+fn run(program: &Program, facts: Vec<Fact>, output: &mut String) -> Result<(), String> {
+    let options = RunOptions {
+        sorted: true,
+        ..RunOptions::default()
+    };
+    let database = run_datalog_with_options(program, facts, &options)
+        .map_err(|e| e.to_string())?
+        .database;
+    for fact in database.facts() {
+        output.push_str(&format!("{};\n", fact));
+    }
+    Ok(())
+}
+
+// The output of a case in the format of `datalogint run`, with a `# <function>` line
+// before the output of every function of a Bril program
+fn evaluate(case: &Case) -> Result<String, String> {
+    let read = |path: &Path| fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e));
+    let program = parse_program(&mut Token::lexer(&read(&case.program)?))
+        .map_err(|e| format!("Error parsing rules: {}", e))?;
+    let input = read(&case.input)?;
+    let mut output = String::new();
+    if case.input.extension().is_some_and(|ext| ext == "json") {
+        for function in &parse_bril(&input)?.func_index {
+            output.push_str(&format!("# {}\n", function.name));
+            run(&program, get_facts_from_bril_fn(function), &mut output)?;
+        }
+    } else {
+        let facts = parse_fact_vector(&mut Token::lexer(&input))
+            .map_err(|e| format!("Error parsing facts: {}", e))?;
+        run(&program, facts, &mut output)?;
+    }
+    Ok(output)
+}
+
+// The lines only in the expected output (-) and only in the actual output (+)
+fn diff(expected: &str, actual: &str) -> String {
+    let expected_lines = expected.lines().collect::<Vec<_>>();
+    let actual_lines = actual.lines().collect::<Vec<_>>();
+    let missing = expected_lines
+        .iter()
+        .filter(|line| !actual_lines.contains(line))
+        .map(|line| format!("-{}", line));
+    let extra = actual_lines
+        .iter()
+        .filter(|line| !expected_lines.contains(line))
+        .map(|line| format!("+{}", line));
+    let lines = missing.chain(extra).collect::<Vec<_>>();
+    if lines.is_empty() {
+        // same lines in a different order
+        format!("-{}\n+{}", expected, actual)
+    } else {
+        lines.join("\n")
+    }
+}
+
+fn main() {
+    let mut bless = false;
+    let mut filter = None;
+    let mut args = env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bless" => {
+                bless = true;
+                filter = args.next_if(|next| !next.starts_with('-')).or(filter);
+            }
+            "--filter" => match args.next() {
+                Some(value) => filter = Some(value),
+                None => {
+                    println!("--filter needs a value");
+                    process::exit(1);
+                }
+            },
+            // e.g. `--test-threads 2`, which cargo test passes on to every test binary
+            _ => {}
+        }
+    }
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let cases = find_cases(&root.join("samples"))
+        .into_iter()
+        .filter(|case| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| case.input.to_string_lossy().contains(filter.as_str()))
+        })
+        .collect::<Vec<_>>();
+    if let Some(filter) = filter.as_ref().filter(|_| cases.is_empty()) {
+        println!("golden: no input matches {}", filter);
+        process::exit(1);
+    }
+
+    let mut failures = 0;
+    for case in &cases {
+        let name = case.input.strip_prefix(root).unwrap().display();
+        let actual = match evaluate(case) {
+            Ok(actual) => actual,
+            Err(e) => {
+                println!("{} ... error: {}", name, e);
+                failures += 1;
+                continue;
+            }
+        };
+        if bless {
+            fs::write(case.expected(), &actual).unwrap();
+            println!("{} ... blessed", name);
+            continue;
+        }
+        match fs::read_to_string(case.expected()) {
+            Ok(expected) if expected == actual => println!("{} ... ok", name),
+            Ok(expected) => {
+                println!("{} ... FAILED\n{}", name, diff(&expected, &actual));
+                failures += 1;
+            }
+            Err(_) => {
+                println!("{} ... no .expected file, run with --bless", name);
+                failures += 1;
+            }
+        }
+    }
+
+    println!(
+        "\ngolden: {} passed, {} failed",
+        cases.len() - failures,
+        failures
+    );
+    if failures > 0 {
+        process::exit(1);
+    }
+}
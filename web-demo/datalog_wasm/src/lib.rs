@@ -1,14 +1,14 @@
-use std::collections::HashSet;
 use std::panic;
-use std::path::Display;
 
 use bril2json::parse_abstract_program_from_read;
-use datalogint::implem::run_datalog;
+use datalogint::external::builtin_externals;
+use datalogint::implem::{run_datalog_with_progress, RunOptions};
 use datalogint::optimize_bril::{self, perform_liveness_analysis};
 use datalogint::parse::{parse_fact_vector, parse_program, Token};
 use datalogint::parse_bril::{
     bril_to_string, convert_abstract_program_to_bril_program, get_facts_from_bril_fn, parse_bril,
 };
+use datalogint::progress::{CancellationToken, Progress};
 use logos::Logos;
 use wasm_bindgen::prelude::*;
 extern crate console_error_panic_hook;
@@ -77,8 +77,63 @@ pub fn analyze_bril_program(bril_src: &str) -> Result<StringPair, JsError> {
     Ok(StringPair::new(running_str.clone(), bril_prog))
 }
 
+const MAX_ITERATIONS: usize = 10_000;
+const MAX_FACTS: usize = 1_000_000;
+
 #[wasm_bindgen]
 pub fn run_datalog_analysis(rules: &str, facts: &str) -> Result<String, JsError> {
+    run_analysis(rules, facts, |_| {}, &CancellationToken::new())
+}
+
+// Same as run_datalog_analysis, but calls `on_progress` after every iteration with an object
+// `{ iteration, stratum, relation_sizes }`. Returning `false` from `on_progress` (or throwing)
+// cancels the evaluation, which then fails with "evaluation cancelled".
+// The page cannot react to a Stop button while evaluation runs on its thread, so the demo
+// runs this in a worker and checks a shared stop flag from `on_progress`.
+#[wasm_bindgen]
+pub fn run_datalog_analysis_with_progress(
+    rules: &str,
+    facts: &str,
+    on_progress: &js_sys::Function,
+) -> Result<String, JsError> {
+    let cancel = CancellationToken::new();
+    run_analysis(
+        rules,
+        facts,
+        |progress| match on_progress.call1(&JsValue::NULL, &progress_to_js(progress)) {
+            Ok(result) if result != JsValue::FALSE => {}
+            _ => cancel.cancel(),
+        },
+        &cancel,
+    )
+}
+
+fn progress_to_js(progress: &Progress) -> JsValue {
+    let relation_sizes = js_sys::Object::new();
+    for (name, size) in &progress.relation_sizes {
+        let _ = js_sys::Reflect::set(&relation_sizes, &name.into(), &(*size as f64).into());
+    }
+    let object = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(
+        &object,
+        &"iteration".into(),
+        &(progress.iteration as f64).into(),
+    );
+    let _ = js_sys::Reflect::set(
+        &object,
+        &"stratum".into(),
+        &(progress.stratum as f64).into(),
+    );
+    let _ = js_sys::Reflect::set(&object, &"relation_sizes".into(), &relation_sizes);
+    object.into()
+}
+
+fn run_analysis(
+    rules: &str,
+    facts: &str,
+    progress: impl FnMut(&Progress),
+    cancel: &CancellationToken,
+) -> Result<String, JsError> {
     // Parse program (rules)
     let mut rules_lexer = Token::lexer(rules);
     let program = match parse_program(&mut rules_lexer) {
@@ -86,18 +141,6 @@ pub fn run_datalog_analysis(rules: &str, facts: &str) -> Result<String, JsError>
         Err(e) => return Err(JsError::new(&format!("Error parsing rules: {}", e))),
     };
 
-    let output_fact_names = program
-        .decls
-        .iter()
-        .filter_map(|decl| {
-            if decl.kind == datalogint::parse::DeclKind::Output {
-                Some(decl.name.clone())
-            } else {
-                None
-            }
-        })
-        .collect::<HashSet<String>>();
-
     // Parse facts
     let mut facts_lexer = Token::lexer(facts);
     let facts = match parse_fact_vector(&mut facts_lexer) {
@@ -105,16 +148,24 @@ pub fn run_datalog_analysis(rules: &str, facts: &str) -> Result<String, JsError>
         Err(e) => return Err(JsError::new(&format!("Error parsing facts: {}", e))),
     };
 
-    // Run the analysis
-    let output_facts = match run_datalog(&program, facts) {
-        Ok(output_facts) => output_facts,
+    // Run the analysis. There is no timeout because std::time::Instant panics on wasm32,
+    // the iteration and fact limits keep a runaway program from freezing the page instead.
+    let options = RunOptions {
+        max_iterations: Some(MAX_ITERATIONS),
+        max_facts: Some(MAX_FACTS),
+        sorted: true,
+        externals: builtin_externals(),
+        ..RunOptions::default()
+    };
+    let output_facts = match run_datalog_with_progress(&program, facts, &options, progress, cancel)
+    {
+        Ok(output) => output.database,
         Err(e) => return Err(JsError::new(&format!("Error running analysis: {}", e))),
     };
 
     // Format the output facts as a string
     let result = output_facts
-        .iter()
-        .filter(|fact| output_fact_names.contains(&fact.name))
+        .facts()
         .fold(String::new(), |acc, fact| format!("{}{}\n", acc, fact));
 
     Ok(result)
@@ -0,0 +1,22 @@
+// Runs datalog programs off the page's thread so that the page can show progress and
+// stop a long evaluation.
+import init, { run_datalog_analysis_with_progress } from './datalog_wasm.js';
+
+const ready = init();
+
+self.onmessage = async (event) => {
+  const { rules, facts, stop } = event.data;
+  await ready;
+  // set to 1 by the page when the Stop button is pressed, only available when the page is
+  // cross-origin isolated (otherwise the page terminates the worker instead)
+  const stopFlag = stop ? new Int32Array(stop) : null;
+  try {
+    const result = run_datalog_analysis_with_progress(rules, facts, (progress) => {
+      self.postMessage({ type: 'progress', progress });
+      return !(stopFlag && Atomics.load(stopFlag, 0) === 1);
+    });
+    self.postMessage({ type: 'result', result });
+  } catch (e) {
+    self.postMessage({ type: 'error', message: e.message });
+  }
+};